@@ -1,13 +1,33 @@
 #![allow(dead_code)]
 use anyhow::{anyhow, Result};
 use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::{DateTime, Local, SecondsFormat, Utc};
 use core::str;
 use reqwest::{Client, Url};
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
-use std::io::{self};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::OnceCell;
 
-use crate::config::Config;
+use crate::{
+    config::{self, ApiStyle, Config},
+    encoding,
+    error::{self, ApiError},
+    mime,
+    ratelimit::RateLimiter,
+    stats,
+    style::{self, Style},
+    transcript,
+};
+
+mod anthropic;
+mod ollama;
 
 #[derive(Debug, Deserialize, Clone)]
 struct Response {
@@ -46,6 +66,9 @@ enum Choice {
 
 #[derive(Debug, Deserialize, Clone)]
 struct NonChatChoice {
+    // Which of the `n` requested choices this is
+    #[serde(default)]
+    index: usize,
     finish_reason: Option<String>,
     text: String,
     error: Option<ErrorResponse>,
@@ -53,6 +76,9 @@ struct NonChatChoice {
 
 #[derive(Debug, Deserialize, Clone)]
 struct NonStreamingChoice {
+    // Which of the `n` requested choices this is
+    #[serde(default)]
+    index: usize,
     // Depends on the model. Ex: 'stop' | 'length' | 'content_filter' | 'tool_calls'
     finish_reason: Option<String>,
     message: Message,
@@ -61,6 +87,9 @@ struct NonStreamingChoice {
 
 #[derive(Debug, Deserialize, Clone)]
 struct StreamingChoice {
+    // Which of the `n` requested choices the delta belongs to
+    #[serde(default)]
+    index: usize,
     finish_reason: Option<String>,
     delta: Delta,
     error: Option<ErrorResponse>,
@@ -69,6 +98,8 @@ struct StreamingChoice {
 #[derive(Debug, Deserialize, Clone)]
 struct Message {
     content: Option<String>,
+    // The reasoning of a reasoning model, kept apart from the content
+    reasoning: Option<String>,
     role: String,
     tool_calls: Option<Vec<ToolCall>>,
 }
@@ -76,6 +107,8 @@ struct Message {
 #[derive(Debug, Deserialize, Clone)]
 struct Delta {
     content: Option<String>,
+    // The next piece of a reasoning model's reasoning
+    reasoning: Option<String>,
     role: Option<String>,
     tool_calls: Option<Vec<ToolCall>>,
 }
@@ -89,6 +122,93 @@ struct ErrorResponse {
     metadata: Option<serde_json::Value>,
 }
 
+impl ErrorResponse {
+    /// Recognizes a prompt that was refused by content moderation, which OpenRouter reports
+    /// with the moderation categories as `reasons` and the offending text as `flagged_input`
+    /// in the metadata.
+    ///
+    /// # Returns
+    /// - The `ApiError::Moderation` describing the refusal, or `None` for any other error.
+    fn moderation(&self) -> Option<ApiError> {
+        let metadata = self.metadata.as_ref()?.as_object()?;
+        if !metadata.contains_key("reasons") && !metadata.contains_key("flagged_input") {
+            return None;
+        }
+        let categories = metadata
+            .get("reasons")
+            .and_then(|reasons| reasons.as_array())
+            .map(|reasons| {
+                reasons
+                    .iter()
+                    .filter_map(|reason| reason.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let flagged_input = metadata
+            .get("flagged_input")
+            .and_then(|input| input.as_str())
+            .map(str::to_string);
+        Some(ApiError::Moderation {
+            categories,
+            flagged_input,
+        })
+    }
+
+    /// Turns an error reported inside a response body into the error returned to the caller.
+    fn into_error(self) -> anyhow::Error {
+        match self.moderation() {
+            Some(moderation) => moderation.into(),
+            None => anyhow!(
+                "API request failed with code {}: {}",
+                self.code,
+                self.describe()
+            ),
+        }
+    }
+
+    /// Describes the error for the user. The provider's name and raw error are pulled out of
+    /// the metadata so that the actual reason for a rejection is easy to find, and whatever
+    /// metadata remains is shown as indented JSON.
+    fn describe(&self) -> String {
+        let mut description = self.message.clone();
+        let Some(mut metadata) = self.metadata.clone() else {
+            return description;
+        };
+
+        if let Some(metadata) = metadata.as_object_mut() {
+            if let Some(provider) = metadata.remove("provider_name") {
+                let provider = provider.as_str().map(str::to_string);
+                description.push_str(&format!(
+                    "\nProvider: {}",
+                    provider.unwrap_or_else(|| "unknown".to_string())
+                ));
+            }
+            if let Some(raw) = metadata.remove("raw") {
+                // providers often send their error as a JSON string inside the JSON
+                let raw = match raw {
+                    serde_json::Value::String(text) => {
+                        serde_json::from_str(&text).unwrap_or(serde_json::Value::String(text))
+                    }
+                    raw => raw,
+                };
+                let raw = match raw {
+                    serde_json::Value::String(text) => text,
+                    raw => serde_json::to_string_pretty(&raw).unwrap_or_default(),
+                };
+                description.push_str(&format!("\nProvider error: {}", raw));
+            }
+            if metadata.is_empty() {
+                return description;
+            }
+        }
+
+        if let Ok(pretty) = serde_json::to_string_pretty(&metadata) {
+            description.push_str(&format!("\nError metadata:\n{}", pretty));
+        }
+        description
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 struct ErrorResponseContainer {
     error: ErrorResponse,
@@ -96,31 +216,352 @@ struct ErrorResponseContainer {
 
 #[derive(Debug, Deserialize, Clone)]
 struct ToolCall {
-    id: String,
-    r#type: String,
+    // Which call of the choice this is; the pieces of a streamed call all share it
+    #[serde(default)]
+    index: usize,
+    // Only sent with the first piece of a streamed call
+    id: Option<String>,
+    r#type: Option<String>,
+    #[serde(default)]
     function: FunctionCall,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 struct FunctionCall {
-    // Define the fields of FunctionCall based on your needs
+    // Only sent with the first piece of a streamed call
+    name: Option<String>,
+    // Usually a string of JSON, which is streamed a piece at a time
+    arguments: Option<serde_json::Value>,
+}
+
+// A tool call put together from the pieces it was streamed in.
+#[derive(Debug, Default)]
+struct ToolCallParts {
+    id: Option<String>,
     name: String,
-    arguments: serde_json::Value,
+    arguments: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct Usage {
+impl ToolCallParts {
+    /// Adds the next piece of the call.
+    fn push(&mut self, call: &ToolCall) {
+        if call.id.is_some() {
+            self.id = call.id.clone();
+        }
+        if let Some(name) = &call.function.name {
+            self.name.push_str(name);
+        }
+        match &call.function.arguments {
+            Some(serde_json::Value::String(arguments)) => self.arguments.push_str(arguments),
+            Some(arguments) => self.arguments.push_str(&arguments.to_string()),
+            None => {}
+        }
+    }
+
+    /// Returns the call as a line of JSON with its `id`, `name` and `arguments`, which are
+    /// parsed when they're valid JSON and otherwise left as a string.
+    fn to_json_line(&self) -> String {
+        let arguments = serde_json::from_str::<serde_json::Value>(&self.arguments)
+            .unwrap_or_else(|_| json!(self.arguments));
+        let call = json!({
+            "id": self.id,
+            "name": self.name,
+            "arguments": arguments,
+        });
+        format!("{}\n", call)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Usage {
     // Including images and tools if any
-    prompt_tokens: u64,
+    pub prompt_tokens: u64,
 
     // The tokens generated
-    completion_tokens: u64,
+    pub completion_tokens: u64,
 
     // Sum of the above two fields
-    total_tokens: u64,
+    pub total_tokens: u64,
+
+    // The credits actually spent on the request; only reported by OpenRouter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+
+    // The prompt tokens read from and written to the prompt cache; only reported by Anthropic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_read_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_write_tokens: Option<u64>,
+}
+
+/// An update delivered to an `ApiClientEventCallback` as the response is received.
+///
+/// For non-streaming requests a single event is sent containing the whole response.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamEvent<'a> {
+    // The text received with this update; may be empty for the final event
+    pub delta: &'a str,
+
+    // All of the text received so far, including `delta`
+    pub cumulative: &'a str,
+
+    // The model generating the response, once the API has reported it
+    pub model: Option<&'a str>,
+
+    // Set once the API reports why the generation stopped
+    pub finish_reason: Option<&'a str>,
+
+    // Only present once the API reports the token usage for the request
+    pub usage: Option<&'a Usage>,
+}
+
+/// Information about a model available from the API's `/v1/models` endpoint.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModelInfo {
+    pub id: String,
+
+    // Only present for endpoints that describe their models, like OpenRouter
+    pub name: Option<String>,
+
+    // The size of the model's context window in tokens, if reported
+    pub context_length: Option<u64>,
+
+    // What the model's tokens cost, if reported
+    pub pricing: Option<ModelPricing>,
+}
+
+/// The price of a model's tokens as OpenRouter lists them: US dollars per token, written as
+/// a decimal string like `"0.0000015"`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModelPricing {
+    pub prompt: Option<String>,
+
+    pub completion: Option<String>,
+}
+
+impl ModelPricing {
+    /// Works out what the tokens of `usage` cost at these prices.
+    ///
+    /// # Returns
+    /// - The cost in US dollars, or `None` if a price is missing or isn't a plain number,
+    ///   like the `-1` OpenRouter lists for models whose price varies.
+    pub fn cost(&self, usage: &Usage) -> Option<f64> {
+        let price = |price: &Option<String>| {
+            price
+                .as_deref()?
+                .parse::<f64>()
+                .ok()
+                .filter(|price| *price >= 0.0)
+        };
+        Some(
+            usage.prompt_tokens as f64 * price(&self.prompt)?
+                + usage.completion_tokens as f64 * price(&self.completion)?,
+        )
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct ModelList {
+    data: Vec<ModelInfo>,
+}
+
+/// The sampling parameters a model supports, from OpenRouter's `/v1/parameters/:model` endpoint.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModelParameters {
+    pub model: String,
+
+    // The request body fields the model honors, like 'temperature' or 'top_k'
+    pub supported_parameters: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct ModelParametersContainer {
+    data: ModelParameters,
+}
+
+/// A single message of a chat conversation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    // One of 'system', 'user' or 'assistant'
+    pub role: String,
+
+    pub content: String,
+}
+
+/// The result of a completion request once the whole response has been received.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Completion {
+    // The identifier the API assigned to the response, if any
+    pub id: Option<String>,
+
+    // The model that actually generated the response, if reported
+    pub model: Option<String>,
+
+    // Unix timestamp of when the response was created, if reported
+    pub created: Option<u64>,
+
+    // All of the generated text that was sent to the callback
+    pub content: String,
+
+    // Why the generation stopped, if the API reported it
+    pub finish_reason: Option<String>,
+
+    // The token usage for the request, if the API reported it
+    pub usage: Option<Usage>,
+}
+
+impl Completion {
+    /// Returns the value of a named piece of metadata for use in an output template.
+    ///
+    /// The supported names are `content`, `model`, `id`, `timestamp`, `finish_reason`,
+    /// `usage.prompt`, `usage.completion`, `usage.total`, `usage.cache_read` and
+    /// `usage.cache_write`. Metadata the API didn't report renders as an empty string,
+    /// while unknown names return `None`.
+    pub fn template_value(&self, name: &str) -> Option<String> {
+        let usage_value = |f: fn(&Usage) -> u64| {
+            self.usage
+                .as_ref()
+                .map(|u| f(u).to_string())
+                .unwrap_or_default()
+        };
+        let value = match name {
+            "content" => self.content.clone(),
+            "model" => self.model.clone().unwrap_or_default(),
+            "id" => self.id.clone().unwrap_or_default(),
+            "timestamp" => {
+                let created = self
+                    .created
+                    .and_then(|created| DateTime::from_timestamp(created as i64, 0))
+                    .unwrap_or_else(Utc::now);
+                created.to_rfc3339()
+            }
+            "finish_reason" => self.finish_reason.clone().unwrap_or_default(),
+            "usage.prompt" => usage_value(|u| u.prompt_tokens),
+            "usage.completion" => usage_value(|u| u.completion_tokens),
+            "usage.total" => usage_value(|u| u.total_tokens),
+            "usage.cache_read" => usage_value(|u| u.cache_read_tokens.unwrap_or_default()),
+            "usage.cache_write" => usage_value(|u| u.cache_write_tokens.unwrap_or_default()),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// The token usage accumulated across every request of a session, shared by the clients
+/// that make them so that `max_total_tokens` can be enforced.
+#[derive(Debug, Default)]
+pub struct SessionUsage {
+    // The sum of the usage of every finished request
+    usage: Mutex<Usage>,
+
+    // How many requests have finished
+    requests: Mutex<u64>,
+}
+
+impl SessionUsage {
+    /// Returns the total number of tokens used so far.
+    pub fn total_tokens(&self) -> u64 {
+        self.usage.lock().unwrap().total_tokens
+    }
+
+    /// Adds the usage of a finished request, returning the new totals and request count.
+    fn add(&self, usage: &Usage) -> (Usage, u64) {
+        let mut total = self.usage.lock().unwrap();
+        total.prompt_tokens += usage.prompt_tokens;
+        total.completion_tokens += usage.completion_tokens;
+        total.total_tokens += usage.total_tokens;
+        let mut requests = self.requests.lock().unwrap();
+        *requests += 1;
+        (total.clone(), *requests)
+    }
+}
+
+/// Receives the text of a response. Being a closure, it can keep state of its own between
+/// calls, such as a buffer gathering the whole response or a file it writes to.
+pub type ApiClientCallback = Box<dyn FnMut(&str) -> io::Result<()> + Send>;
+/// Receives each `StreamEvent` of a response. Like `ApiClientCallback`, it's a closure that
+/// can keep state of its own between calls, such as the events gathered so far.
+pub type ApiClientEventCallback = Box<dyn FnMut(&StreamEvent) + Send>;
+
+// The two flavors of callback that an `ApiClient` can report results through.
+enum Callback {
+    // Both are behind a lock since they need to be borrowed mutably to be called
+    Delta(Mutex<ApiClientCallback>),
+    Event(Mutex<ApiClientEventCallback>),
+}
+
+// Applies `--trim` and `--no-trailing-newline` to streamed text. Whitespace at the end of
+// a delta is held back until more text arrives, so that whatever is still held back once
+// the response ends is what gets removed.
+#[derive(Debug, Default)]
+struct OutputShaper {
+    // Set once any text has been let through
+    started: bool,
+
+    // Trailing whitespace that hasn't been let through yet
+    pending: String,
+}
+
+impl OutputShaper {
+    /// Returns the part of `delta` that can be sent on to the callback right away.
+    fn shape(&mut self, delta: &str, trim: bool, no_trailing_newline: bool) -> String {
+        let delta = if trim && !self.started {
+            delta.trim_start()
+        } else {
+            delta
+        };
+        let kept = if trim {
+            delta.trim_end()
+        } else if no_trailing_newline {
+            delta.trim_end_matches(['\n', '\r'])
+        } else {
+            delta
+        };
+
+        if kept.is_empty() {
+            if self.started {
+                self.pending.push_str(delta);
+            }
+            return String::new();
+        }
+
+        self.started = true;
+        let mut text = std::mem::take(&mut self.pending);
+        text.push_str(kept);
+        self.pending.push_str(&delta[kept.len()..]);
+        text
+    }
+}
+
+// The timing of a streamed response, recorded for `token_stats`.
+#[derive(Debug, Default)]
+struct StreamTimings {
+    // When the request was first sent
+    sent: Option<Instant>,
+
+    // When each piece of text arrived
+    deltas: Vec<Instant>,
+}
+
+// How many of the deltas of a streamed response carried no text, for `show_empty_deltas`.
+#[derive(Debug, Default)]
+struct DeltaCounts {
+    // Every delta received, not counting the ones that only end the response or report usage
+    total: u64,
+
+    // The deltas that were empty or only whitespace
+    empty: u64,
 }
 
-pub type ApiClientCallback = fn(&str);
+// Streamed text held back so that writes are spread out by `min_interval`.
+#[derive(Debug, Default)]
+struct Pacing {
+    // The text waiting to be written
+    pending: String,
+
+    // When text was last written for the current request
+    last_write: Option<Instant>,
+}
 
 pub struct ApiClient {
     // The configuration for the API client
@@ -128,7 +569,60 @@ pub struct ApiClient {
 
     // The callback that will get either the entire response when received,
     // or a streaming update, piece by piece, if streaming is enabled in `config`.
-    callback: ApiClientCallback,
+    callback: Callback,
+
+    // The parameters supported by the configured model, fetched at most once; `None`
+    // if they couldn't be fetched.
+    model_parameters: Arc<OnceCell<Option<ModelParameters>>>,
+
+    // The HTTP client for every request, built on first use so its connections get reused
+    http_client: Arc<OnceCell<Client>>,
+
+    // The models listed by the API, fetched at most once for `verify_model`; `None` if the
+    // list couldn't be fetched.
+    models: Arc<OnceCell<Option<Vec<ModelInfo>>>>,
+
+    // Tracks the whitespace held back from the delta callback for the current request
+    shaper: Mutex<OutputShaper>,
+
+    // Streamed text gathered for the delta callback until `chunk_size` is reached, or until
+    // a line is finished for `timestamps`
+    chunk: Mutex<String>,
+
+    // When the current request was sent and its text arrived, for `token_stats`
+    timings: Mutex<StreamTimings>,
+
+    // Whether the `echo_model_in_output` header has been written for the current request
+    model_echoed: Mutex<bool>,
+
+    // The deltas of the current request that were empty, for `show_empty_deltas`
+    delta_counts: Mutex<DeltaCounts>,
+
+    // The streamed text waiting for `min_interval` to pass before it's written
+    pacing: Mutex<Pacing>,
+
+    // Whether the text written to the delta callback so far ends partway through a line
+    line_open: Mutex<bool>,
+
+    // The streamed text of each choice after the first, held back until the stream ends
+    // when `choices` asks for several
+    choices: Mutex<BTreeMap<usize, String>>,
+
+    // The tool calls of the current request as they're streamed, by the index of their choice
+    // and then of the call
+    tool_calls: Mutex<BTreeMap<(usize, usize), ToolCallParts>>,
+
+    // Earlier messages of the conversation that are sent ahead of the prompt
+    history: Vec<ChatMessage>,
+
+    // The usage of the session this client's requests belong to
+    session: Arc<SessionUsage>,
+
+    // The API's rate limit budget, as last reported to any client sharing it
+    rate_limiter: Arc<RateLimiter>,
+
+    // Where diagnostics and warnings are written; stderr outside of tests
+    diagnostics: Mutex<Box<dyn Write + Send>>,
 }
 
 /// `ApiClient` is a struct responsible for interacting with an OpenAI compatible text generation API.
@@ -138,8 +632,82 @@ pub struct ApiClient {
 /// request body, and sends it to the OpenRouter AI API. It then processes the response, handling both
 /// streaming and non-streaming responses, and outputs the results to the callback function provided.
 impl ApiClient {
-    pub fn new(config: Config, callback: ApiClientCallback) -> Self {
-        ApiClient { config, callback }
+    pub fn new(
+        config: Config,
+        callback: impl FnMut(&str) -> io::Result<()> + Send + 'static,
+    ) -> Self {
+        Self::with_callback(config, Callback::Delta(Mutex::new(Box::new(callback))))
+    }
+
+    /// Creates an `ApiClient` that reports each update as a `StreamEvent`, carrying the
+    /// cumulative text, finish reason and usage alongside the newly received delta.
+    pub fn with_event_callback(
+        config: Config,
+        callback: impl FnMut(&StreamEvent) + Send + 'static,
+    ) -> Self {
+        Self::with_callback(config, Callback::Event(Mutex::new(Box::new(callback))))
+    }
+
+    /// Creates an `ApiClient` reporting through either flavor of `callback`, with the
+    /// per-request state empty and nothing shared with other clients yet.
+    fn with_callback(config: Config, callback: Callback) -> Self {
+        ApiClient {
+            config,
+            callback,
+            model_parameters: Arc::new(OnceCell::new()),
+            http_client: Arc::new(OnceCell::new()),
+            models: Arc::new(OnceCell::new()),
+            shaper: Mutex::new(OutputShaper::default()),
+            chunk: Mutex::new(String::new()),
+            timings: Mutex::new(StreamTimings::default()),
+            model_echoed: Mutex::new(false),
+            delta_counts: Mutex::new(DeltaCounts::default()),
+            pacing: Mutex::new(Pacing::default()),
+            line_open: Mutex::new(false),
+            choices: Mutex::new(BTreeMap::new()),
+            tool_calls: Mutex::new(BTreeMap::new()),
+            history: Vec::new(),
+            session: Arc::new(SessionUsage::default()),
+            rate_limiter: Arc::new(RateLimiter::default()),
+            diagnostics: Mutex::new(Box::new(io::stderr())),
+        }
+    }
+
+    /// Sends the diagnostics and warnings to `sink` instead of stderr so tests can read them.
+    #[cfg(test)]
+    fn with_diagnostics(mut self, sink: impl Write + Send + 'static) -> Self {
+        self.diagnostics = Mutex::new(Box::new(sink));
+        self
+    }
+
+    /// Sets the earlier messages of the conversation to send ahead of the prompt. They're
+    /// ignored for plain completions.
+    pub fn with_history(mut self, history: Vec<ChatMessage>) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Counts this client's requests as part of a session shared with other clients, such as
+    /// the other turns of a chat or the other lines of a batch.
+    pub fn with_session(mut self, session: Arc<SessionUsage>) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// Shares the HTTP client, along with its open connections, and the cached model parameters
+    /// and models list of another client, which must be configured for the same API and model.
+    pub fn with_shared_state(mut self, other: &ApiClient) -> Self {
+        self.http_client = other.http_client.clone();
+        self.model_parameters = other.model_parameters.clone();
+        self.models = other.models.clone();
+        self
+    }
+
+    /// Shares a rate limiter with other clients so that requests sent at the same time slow
+    /// down together as the API's rate limit runs low.
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
     }
 
     /// Sends a completion request to the OpenRouter AI API based on the configuration provided.
@@ -150,183 +718,1179 @@ impl ApiClient {
     /// responses, and outputs the results to callback function passed in when creating the `ApiClient` object.
     ///
     /// # Returns:
-    /// - `Result<()>`: Returns Ok() if the completion request is successful and the response is
+    /// - `Result<Completion>`: Returns the `Completion` if the request is successful and the response is
     ///   processed without errors or an Err if there is a failure in reading the
     ///   prompt, sending the request, or processing the response.
-    pub async fn do_completion(&self) -> Result<()> {
-        // Read the prompt from stdin if the prompt wasn't supplied
-        let prompt = match &self.config.prompt {
-            Some(p) => p.clone(),
-            None => io::read_to_string(io::stdin())?,
-        };
+    pub async fn do_completion(&self) -> Result<Completion> {
+        // each request starts without any held back whitespace
+        *self.shaper.lock().unwrap() = OutputShaper::default();
+        self.chunk.lock().unwrap().clear();
+        *self.timings.lock().unwrap() = StreamTimings::default();
+        *self.model_echoed.lock().unwrap() = false;
+        *self.delta_counts.lock().unwrap() = DeltaCounts::default();
+        *self.pacing.lock().unwrap() = Pacing::default();
+        *self.line_open.lock().unwrap() = false;
+        self.choices.lock().unwrap().clear();
+        self.tool_calls.lock().unwrap().clear();
 
-        // determine if we're using the chat-compltion endpoint or not
-        let url = if self.config.plain {
-            format!("{}/v1/completions", self.config.api)
-        } else {
-            format!("{}/v1/chat/completions", self.config.api)
-        };
+        let (prompt, url, mut body) = self.prepare_request().await?;
+        self.verify_model().await?;
+        self.check_supported_parameters(&body).await?;
 
-        // build the response body for the request using the prompt and all of
-        // the configuration settings for this ApiClient.
-        let body = self.build_request_body(&prompt);
+        self.check_token_budget(&prompt)?;
 
         // post the request out to the API endpoint
-        let client = Client::new();
-        let response = client
-            .post(url)
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.config.api_key))
-            .header("HTTP-Referer", "https://github.com/tbogdala/evocaition")
-            .header("X-Title", "evocaition")
-            .json(&body)
-            .send()
-            .await?;
+        self.timings.lock().unwrap().sent = Some(Instant::now());
+        let mut response = self.send_with_retries(&url, &body).await?;
         if !response.status().is_success() {
-            let error_message = format!(
-                "API request failed with status {}: {}",
-                response.status(),
-                response
-                    .text()
-                    .await
-                    .unwrap_or_else(|_| "Unable to read response body".to_string())
-            );
-            return Err(anyhow!(error_message));
+            let status = response.status();
+            let error_text = read_error_body(response).await;
+
+            // if the prompt didn't fit and there's a larger context model configured,
+            // send the same request to that model instead of giving up.
+            match &self.config.fallback_model {
+                Some(fallback) if is_context_length_error(&error_text) => {
+                    self.notify(&format!(
+                        "Prompt exceeds the context length of '{}'; retrying with fallback model '{}'",
+                        self.config.model_id, fallback
+                    ));
+                    body["model"] = json!(fallback);
+                    response = self.send_with_retries(&url, &body).await?;
+                    if !response.status().is_success() {
+                        let status = response.status();
+                        let error_text = read_error_body(response).await;
+                        return Err(status_error(status, &error_text));
+                    }
+                }
+                _ => return Err(status_error(status, &error_text)),
+            }
         }
 
         // handle the response in one of two ways depending on whether or not 'streaming'
         // is configured.
-        if self.config.stream {
-            self.process_streaming_response(response).await?;
+        let mut completion = if self.config.raw {
+            self.raw_response(response).await?;
+            Completion::default()
+        } else if self.config.stream {
+            if self.config.passthrough {
+                self.passthrough_streaming_response(response).await?;
+                Completion::default()
+            } else {
+                self.process_streaming_response(response).await?
+            }
         } else {
-            let response_text = response.text().await?;
-            self.process_non_streaming_response(&response_text)?;
+            let response_text = response.text().await.map_err(|e| self.network_error(e))?;
+            self.log_traffic(2, &format!("<-- {}", response_text));
+            self.process_non_streaming_response(&response_text)?
+        };
+
+        completion.content = self.shape_content(&completion.content);
+
+        // providers that don't report usage still count against the budget by estimate
+        let usage = completion.usage.clone().unwrap_or_else(|| {
+            let prompt_tokens = self.estimate_prompt_tokens(&prompt);
+            let completion_tokens = estimate_tokens(&completion.content);
+            Usage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+                ..Default::default()
+            }
+        });
+        let session_usage = self.session.add(&usage);
+
+        // a cost the provider didn't report is worked out from the model's listed pricing
+        let estimated_cost = match (&completion.usage, self.config.show_cost) {
+            (Some(usage), true) if usage.cost.is_none() => {
+                self.estimate_cost(&completion, usage).await
+            }
+            _ => None,
+        };
+        self.report_completion(&completion, session_usage, estimated_cost);
+        if let Some(path) = &self.config.transcript_log {
+            if let Err(e) = transcript::append(path, &self.transcript_entry(&prompt, &completion)) {
+                self.warn(&format!(
+                    "unable to write to the transcript log '{}': {}",
+                    path, e
+                ));
+            }
         }
+        Ok(completion)
+    }
 
+    /// Refuses to send the request if it would take the session past `max_total_tokens`.
+    ///
+    /// The request's size is estimated from the prompt and the conversation so far, plus
+    /// `max_tokens` if it's set, since the real usage isn't known until the response arrives.
+    fn check_token_budget(&self, prompt: &str) -> Result<()> {
+        let Some(max_total_tokens) = self.config.max_total_tokens else {
+            return Ok(());
+        };
+        let used = self.session.total_tokens();
+        let estimate = self.estimate_prompt_tokens(prompt)
+            + u64::from(self.config.max_tokens.unwrap_or_default());
+        if used + estimate > max_total_tokens {
+            return Err(anyhow!(
+                "The session has used {} tokens and this request (~{} tokens) would exceed the --max-total-tokens budget of {}",
+                used,
+                estimate,
+                max_total_tokens
+            ));
+        }
         Ok(())
     }
 
-    /// Constructs the request body for an API call based on the provided prompt and configuration.
-    ///
-    /// The function constructs a JSON request body based on the configuration specified in `config`.
-    /// The structure of the request body differs slightly depending on whether plain mode is enabled or not:
-    ///
-    /// - **Plain Mode (self.config.plain = true):**
-    ///   - Includes only the `model`, `prompt`, and `stream` fields.
-    ///
-    /// - **Chat Mode (self.config.plain = false):**
-    ///   - Includes the `model`, `messages`, and `stream` fields.
-    ///   - If an image file path is provided (`self.config.image_file`), the function includes the image
-    ///     in the `messages` array. If the image provided is a URL, then just the URL is added to the prompt.
-    ///     Otherwise it is assumed to be a filesystem path and the image is read from the file system,
-    ///     converted to base64, and the MIME type is determined based on the file extension.
-    ///   - If no image file is provided, only the user's prompt is included in the `messages` array.
-    ///
-    /// Additionally, the function optionally includes other fields (`max_tokens`, `temperature`, `top_k`,
-    /// `top_p`, `min_p`, `repetition_penalty`, `seed`, etc...) in the request body if they are set in the configuration.
-    ///
-    /// # Parameters
-    /// - `prompt` - A string slice representing the user's input prompt to be sent to the model.
+    /// Roughly estimates the prompt tokens of a request from the prompt and the history.
+    fn estimate_prompt_tokens(&self, prompt: &str) -> u64 {
+        estimate_tokens(prompt)
+            + self
+                .history
+                .iter()
+                .map(|message| estimate_tokens(&message.content))
+                .sum::<u64>()
+    }
+
+    /// Estimates the cost of a completion from the pricing the models list gives for the model
+    /// that served it. The list is fetched at most once for the life of the client.
     ///
     /// # Returns
-    /// A `serde_json::Value` representing the JSON request body to be sent in the API call.
-    fn build_request_body(&self, prompt: &str) -> serde_json::Value {
-        let mut body = if self.config.plain {
-            json!({
-                "model": self.config.model_id,
-                "prompt": prompt,
-                "stream": self.config.stream,
-            })
-        } else {
-            // Handle image inclusion if config.image_file is set
-            let messages = if let Some(image_path) = &self.config.image_file {
-                let image_content = match Url::parse(image_path) {
-                    Ok(_url) => image_path.clone(),
-                    Err(_) => {
-                        // Determine the image type based on the file extension
-                        let mime_type = match image_path.split('.').last().unwrap_or_default() {
-                            "jpg" | "jpeg" => Some("image/jpeg"),
-                            "png" => Some("image/png"),
-                            "webp" => Some("image/webp"),
-                            _ => None,
-                        };
-
-                        if let Some(mime_type) = mime_type {
-                            // Read the image file
-                            let image_data =
-                                std::fs::read(image_path).expect("Failed to read image file");
-                            // Encode image to base64
-                            format!(
-                                "data:{};base64,{}",
-                                mime_type,
-                                BASE64_STANDARD.encode(&image_data)
-                            )
-                        } else {
-                            "".to_string()
-                        }
-                    }
-                };
-
-                vec![
-                    json!({
-                        "role": "user",
-                        "content":[
-                            {
-                                "type": "image_url",
-                                "image_url": {
-                                    "url":  image_content,
-                                },
-                            },
-                        ]
-                    }),
-                    json!({
-                        "role": "user",
-                        "content": prompt
-                    }),
-                ]
-            } else {
-                vec![json!({
-                    "role": "user",
-                    "content": prompt,
-                })]
-            };
-            json!({
-                "model": self.config.model_id,
-                "messages": messages,
-                "stream": self.config.stream,
-            })
-        };
+    /// - The cost in US dollars, or `None` if the list couldn't be fetched or doesn't give a
+    ///   price for the model.
+    async fn estimate_cost(&self, completion: &Completion, usage: &Usage) -> Option<f64> {
+        let models = self
+            .models
+            .get_or_init(|| async { self.list_models().await.ok() })
+            .await
+            .as_ref()?;
+        // the model that served the request, or the one asked for if it wasn't reported
+        [
+            completion.model.as_deref(),
+            Some(self.config.model_id.as_str()),
+        ]
+        .into_iter()
+        .flatten()
+        .find_map(|id| models.iter().find(|m| m.id == id))?
+        .pricing
+        .as_ref()?
+        .cost(usage)
+    }
 
-        // add in some optional parameters to the request
-        if let Some(max_tokens) = self.config.max_tokens {
-            body["max_tokens"] = json!(max_tokens);
-        }
-        if let Some(temp) = self.config.temp {
-            body["temperature"] = json!(temp);
+    /// Writes any requested diagnostics about a finished completion to stderr, along with
+    /// the usage of the session so far. `estimated_cost` stands in for a cost the provider
+    /// didn't report.
+    fn report_completion(
+        &self,
+        completion: &Completion,
+        session_usage: (Usage, u64),
+        estimated_cost: Option<f64>,
+    ) {
+        if self.config.show_finish_reason {
+            self.report(&format!(
+                "finish_reason: {}",
+                completion.finish_reason.as_deref().unwrap_or("unknown")
+            ));
         }
-        if let Some(top_k) = self.config.top_k {
-            body["top_k"] = json!(top_k);
+        if self.config.show_usage {
+            match &completion.usage {
+                Some(usage) => self.report(&format!(
+                    "usage: {} prompt + {} completion = {} total",
+                    usage.prompt_tokens, usage.completion_tokens, usage.total_tokens
+                )),
+                None => self.warn("the provider did not report the token usage"),
+            }
+            let (total, requests) = session_usage;
+            if requests > 1 || self.config.max_total_tokens.is_some() {
+                let budget = self
+                    .config
+                    .max_total_tokens
+                    .map(|max| format!(" of the {} token budget", max))
+                    .unwrap_or_default();
+                self.report(&format!(
+                    "session: {} total tokens over {} requests{}",
+                    total.total_tokens, requests, budget
+                ));
+            }
+            if self.config.compare_usage {
+                if let Some(usage) = &completion.usage {
+                    self.report(&stats::usage_ratio_report("request", usage));
+                }
+                if requests > 1 {
+                    self.report(&stats::usage_ratio_report("session", &total));
+                }
+            }
         }
-        if let Some(top_p) = self.config.top_p {
-            body["top_p"] = json!(top_p);
+        if self.config.token_stats && !self.config.quiet {
+            let timings = self.timings.lock().unwrap();
+            let first_token = match (timings.sent, timings.deltas.first()) {
+                (Some(sent), Some(first)) => format!("{}ms", (*first - sent).as_millis()),
+                _ => "n/a".to_string(),
+            };
+            // the rate of generation is measured from the first piece of text to the last
+            let generation = match (timings.deltas.first(), timings.deltas.last()) {
+                (Some(first), Some(last)) => *last - *first,
+                _ => Duration::ZERO,
+            };
+            let tokens_per_second = match &completion.usage {
+                Some(usage) if !generation.is_zero() => format!(
+                    "{:.1}",
+                    usage.completion_tokens as f64 / generation.as_secs_f64()
+                ),
+                _ => "n/a".to_string(),
+            };
+            self.report(&format!(
+                "token stats: {} deltas, time to first token {}, {} tokens/s",
+                timings.deltas.len(),
+                first_token,
+                tokens_per_second
+            ));
+            let gaps: Vec<Duration> = timings.deltas.windows(2).map(|w| w[1] - w[0]).collect();
+            if let Some(report) = stats::latency_report(&gaps) {
+                self.report(&report);
+            }
         }
-        if let Some(min_p) = self.config.min_p {
-            body["min_p"] = json!(min_p);
+        if self.config.show_empty_deltas {
+            let counts = self.delta_counts.lock().unwrap();
+            self.notify(&format!(
+                "empty deltas: {} of {} deltas had no content",
+                counts.empty, counts.total
+            ));
         }
-        if let Some(rep_pen) = self.config.rep_pen {
-            body["repetition_penalty"] = json!(rep_pen);
+        if self.config.show_cost {
+            match (completion.usage.as_ref().and_then(|u| u.cost), estimated_cost) {
+                (Some(cost), _) => self.report(&format!("cost: ${:.6}", cost)),
+                (None, Some(cost)) => {
+                    self.report(&format!("cost: ~${:.6} (estimated from the model's pricing)", cost))
+                }
+                (None, None) => self.warn(
+                    "the provider did not report the cost of the request, and the model's pricing is unknown",
+                ),
+            }
         }
-        if let Some(seed) = self.config.seed {
-            body["seed"] = json!(seed);
+        if self.config.cache_context && self.config.api_style == ApiStyle::Anthropic {
+            if let Some(usage) = &completion.usage {
+                self.report(&format!(
+                    "cache: {} tokens read, {} tokens written",
+                    usage.cache_read_tokens.unwrap_or_default(),
+                    usage.cache_write_tokens.unwrap_or_default()
+                ));
+            }
         }
+    }
 
-        body
+    /// Builds the entry recorded in the transcript log for a finished completion.
+    fn transcript_entry(&self, prompt: &str, completion: &Completion) -> serde_json::Value {
+        let mut entry = json!({
+            "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            "model": completion.model.as_deref().unwrap_or(&self.config.model_id),
+            "prompt": prompt,
+            "completion": completion.content,
+            "finish_reason": completion.finish_reason,
+        });
+        if let Some(usage) = &completion.usage {
+            entry["usage"] = json!({
+                "prompt_tokens": usage.prompt_tokens,
+                "completion_tokens": usage.completion_tokens,
+                "total_tokens": usage.total_tokens,
+            });
+        }
+        entry
     }
 
-    /// Processes a streaming HTTP response, handling JSON data chunks and invoking callbacks for each message.
+    /// Returns the HTTP client used for every request to the API, building it the first time.
+    /// It's kept for the client's later requests, and those of clients sharing its state, so
+    /// that they reuse its open connections.
     ///
-    /// This function asynchronously reads chunks from a `reqwest::Response` object, decodes them from UTF-8,
-    /// and processes lines that start with the prefix "data: ". Each valid JSON message is parsed into a `Response`
+    /// Proxies are picked up from the usual `HTTPS_PROXY`/`HTTP_PROXY` environment variables.
+    /// If `http1_only` is set, HTTP/2 is never negotiated, which some proxies need in order
+    /// to pass streamed responses through.
+    async fn http_client(&self) -> Result<&Client> {
+        self.http_client
+            .get_or_try_init(|| async {
+                let mut builder = Client::builder();
+                if self.config.http1_only {
+                    builder = builder.http1_only();
+                }
+                if let Some(max_connections) = self.config.max_connections {
+                    builder = builder.pool_max_idle_per_host(max_connections);
+                }
+                // a read timeout rather than a total one, so that it catches a stalled
+                // provider without cutting off a long stream that's still arriving
+                if let Some(timeout) = self.config.timeout {
+                    let timeout = Duration::from_secs(timeout);
+                    builder = builder.connect_timeout(timeout).read_timeout(timeout);
+                }
+                builder.build()
+            })
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Fetches the list of models available from the API endpoint.
+    ///
+    /// # Returns
+    /// - The `ModelInfo` for every model listed by the `/v1/models` endpoint, or an `Err` if the
+    ///   request failed or the response couldn't be parsed.
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>> {
+        let model_list = self.get_json::<ModelList>("/v1/models").await?;
+        Ok(model_list.data)
+    }
+
+    /// Fetches the list of models available from the API endpoint exactly as it describes
+    /// them, for passing on to other tools.
+    ///
+    /// # Returns
+    /// - The `data` array of the `/v1/models` response, or an `Err` if the request failed or
+    ///   the response wasn't JSON.
+    pub async fn list_models_json(&self) -> Result<serde_json::Value> {
+        let mut model_list = self.get_json::<serde_json::Value>("/v1/models").await?;
+        Ok(model_list["data"].take())
+    }
+
+    /// Fetches the sampling parameters that the configured model supports.
+    ///
+    /// This uses OpenRouter's `/v1/parameters/:model` endpoint, so other endpoints are
+    /// unlikely to support it.
+    ///
+    /// # Returns
+    /// - The `ModelParameters` for `model_id`, or an `Err` if the request failed or the
+    ///   response couldn't be parsed.
+    pub async fn fetch_model_parameters(&self) -> Result<ModelParameters> {
+        let path = format!("/v1/parameters/{}", self.config.model_id);
+        let container = self.get_json::<ModelParametersContainer>(&path).await?;
+        Ok(container.data)
+    }
+
+    /// Sends an authorized GET request for `path` on the API endpoint and parses the JSON response.
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let url = format!("{}{}", self.config.api, path);
+        self.log_traffic(1, &format!("--> GET {}", url));
+        let response = self
+            .http_client()
+            .await?
+            .get(url)
+            .header("Authorization", format!("Bearer {}", self.config.api_key))
+            .send()
+            .await
+            .map_err(|e| self.network_error(e))?;
+        self.log_traffic(1, &format!("<-- {}", response.status()));
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = read_error_body(response).await;
+            return Err(status_error(status, &error_text));
+        }
+
+        response
+            .json::<T>()
+            .await
+            .map_err(|e| self.network_error(e))
+    }
+
+    /// Turns a failure to talk to the API into an error, telling a `--timeout` running out
+    /// apart from other network problems.
+    fn network_error(&self, error: reqwest::Error) -> anyhow::Error {
+        match (error.is_timeout(), self.config.timeout) {
+            (true, Some(seconds)) => ApiError::Timeout { seconds }.into(),
+            _ => error.into(),
+        }
+    }
+
+    /// Computes a `max_tokens` value that fills the rest of the model's context window.
+    ///
+    /// The model's context length is taken from `context_length` if it was given, which is
+    /// needed for servers whose models list doesn't report it, and otherwise looked up from
+    /// the models list. The estimated size of the prompt, plus a safety margin, is subtracted from it. A warning is written to
+    /// stderr if the remaining budget is tiny, and if there's no room left at all `None` is
+    /// returned so that the request is sent without a `max_tokens` value.
+    ///
+    /// # Parameters
+    /// - `prompt` - The resolved prompt that will be sent to the model.
+    ///
+    /// # Returns
+    /// - The number of tokens to request, `None` if the prompt already fills the context, or
+    ///   an `Err` if the model's context length couldn't be determined.
+    async fn auto_max_tokens(&self, prompt: &str) -> Result<Option<u32>> {
+        const SAFETY_MARGIN_TOKENS: u64 = 128;
+        const TINY_BUDGET_TOKENS: u64 = 256;
+
+        let context_length = match self.config.context_length {
+            Some(context_length) => context_length,
+            None => self
+                .list_models()
+                .await?
+                .iter()
+                .find(|m| m.id == self.config.model_id)
+                .and_then(|m| m.context_length)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Unable to determine the context length of '{}' for --max-tokens-auto; give it with --context-length",
+                        self.config.model_id
+                    )
+                })?,
+        };
+
+        let used = estimate_tokens(prompt) + SAFETY_MARGIN_TOKENS;
+        if used >= context_length {
+            self.warn(&format!(
+                "the prompt (~{} tokens) leaves no room in the {} token context of '{}'",
+                used, context_length, self.config.model_id
+            ));
+            return Ok(None);
+        }
+
+        let remaining = context_length - used;
+        if remaining < TINY_BUDGET_TOKENS {
+            self.warn(&format!(
+                "only ~{} tokens remain in the context of '{}' for the completion",
+                remaining, self.config.model_id
+            ));
+        }
+        Ok(Some(u32::try_from(remaining).unwrap_or(u32::MAX)))
+    }
+
+    /// Posts the request body to the given URL, retrying up to `max_retries` times if the
+    /// request fails with a transient error. The delay before each retry starts at
+    /// `retry_base_ms` and doubles every time, with some random jitter added, unless a `429`
+    /// response says how long to wait with a `Retry-After` header. If waiting would exceed
+    /// `retry_budget`, the last failure is returned right away instead.
+    ///
+    /// # Returns
+    /// - The last response received regardless of its HTTP status, or the last `Err` if the
+    ///   request couldn't be sent at all.
+    async fn send_with_retries(
+        &self,
+        url: &str,
+        body: &serde_json::Value,
+    ) -> Result<reqwest::Response> {
+        let started = Instant::now();
+        let mut attempt = 0;
+        loop {
+            if let Some(delay) = self.rate_limiter.reserve() {
+                self.notify(&format!(
+                    "Rate limit running low; waiting {}ms before sending the request",
+                    delay.as_millis()
+                ));
+                tokio::time::sleep(delay).await;
+            }
+            let result = self.send_request(url, body).await;
+            if let Ok(response) = &result {
+                self.log_traffic(1, &format!("<-- {}", response.status()));
+                self.record_rate_limit(response.headers());
+            }
+            let failure = match &result {
+                Ok(response) if is_retryable_status(response.status()) => {
+                    format!("status {}", response.status())
+                }
+                Ok(_) => return result,
+                Err(e) => e.to_string(),
+            };
+            if attempt >= self.config.max_retries {
+                return result;
+            }
+
+            let retry_after = match &result {
+                Ok(response) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    retry_after(response.headers())
+                }
+                _ => None,
+            };
+            let delay = retry_after.unwrap_or_else(|| {
+                with_jitter(Duration::from_millis(
+                    self.config
+                        .retry_base_ms
+                        .saturating_mul(1 << attempt.min(16)),
+                ))
+            });
+            if let Some(budget) = self.config.retry_budget {
+                if started.elapsed() + delay
+                    > Duration::try_from_secs_f64(budget).unwrap_or_default()
+                {
+                    self.notify(&format!("Retry budget of {}s exhausted; giving up", budget));
+                    return result;
+                }
+            }
+
+            attempt += 1;
+            self.notify(&format!(
+                "Request failed with {}; retrying in {}ms (retry {} of {})",
+                failure,
+                delay.as_millis(),
+                attempt,
+                self.config.max_retries
+            ));
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Records the rate limit budget reported with a response, writing it to stderr if
+    /// `verbose` is set.
+    fn record_rate_limit(&self, headers: &reqwest::header::HeaderMap) {
+        let Some(rate_limit) = self.rate_limiter.record(headers) else {
+            return;
+        };
+        if self.config.verbose > 0 {
+            let limit = rate_limit
+                .limit
+                .map(|limit| format!(" of {}", limit))
+                .unwrap_or_default();
+            let reset = rate_limit
+                .reset
+                .map(|reset| {
+                    let until = reset.saturating_duration_since(Instant::now());
+                    format!(", resets in {:.1}s", until.as_secs_f64())
+                })
+                .unwrap_or_default();
+            self.report(&format!(
+                "rate limit: {}{} requests remaining{}",
+                rate_limit.remaining, limit, reset
+            ));
+        }
+    }
+
+    /// Works out everything about the request that can be known without talking to the
+    /// completion endpoint: the prompt, the URL to post to and the request body.
+    ///
+    /// # Returns
+    /// - The prompt, URL and body, or an `Err` if the prompt couldn't be read or the request
+    ///   isn't valid.
+    async fn prepare_request(&self) -> Result<(String, String, serde_json::Value)> {
+        // Read the prompt from stdin if the prompt wasn't supplied
+        let prompt = match &self.config.prompt {
+            Some(p) => p.clone(),
+            None => encoding::read_stdin(&self.config.input_encoding)?,
+        };
+
+        // let the user know which seed was derived so the run can be repeated
+        if self.config.seed_from_prompt {
+            self.notify(&format!(
+                "Derived seed from prompt: {}",
+                seed_from_prompt(&prompt)
+            ));
+        }
+
+        if self.config.show_prompt_hash {
+            self.notify(&format!("prompt sha256: {}", sha256_hex(prompt.as_bytes())));
+        }
+
+        // determine which endpoint to use based on the API style and whether or not
+        // we're using the chat-completion endpoint
+        let url = match self.config.api_style {
+            ApiStyle::Anthropic if self.config.plain => {
+                return Err(anyhow!(
+                    "'--plain' is not supported with the anthropic API style"
+                ));
+            }
+            ApiStyle::Anthropic => format!("{}/v1/messages", self.config.api),
+            ApiStyle::Ollama if self.config.plain => {
+                return Err(anyhow!(
+                    "'--plain' is not supported with the ollama API style"
+                ));
+            }
+            ApiStyle::Ollama => format!("{}/api/chat", self.config.api),
+            ApiStyle::OpenAi if self.config.plain => format!("{}/v1/completions", self.config.api),
+            ApiStyle::OpenAi => format!("{}/v1/chat/completions", self.config.api),
+        };
+
+        // build the response body for the request using the prompt and all of
+        // the configuration settings for this ApiClient.
+        let mut body = self.build_request_body(&prompt)?;
+        if self.config.cache_context && self.config.api_style != ApiStyle::Anthropic {
+            self.warn(
+                "--cache-context is only supported by the anthropic API style and will be ignored",
+            );
+        }
+        if self.config.max_tokens_auto {
+            if let Some(max_tokens) = self.auto_max_tokens(&prompt).await? {
+                match self.config.api_style {
+                    ApiStyle::Ollama => body["options"]["num_predict"] = json!(max_tokens),
+                    _ => body["max_tokens"] = json!(max_tokens),
+                }
+            }
+        }
+        if self.config.show_request_hash {
+            self.notify(&format!(
+                "request sha256: {}",
+                sha256_hex(body.to_string().as_bytes())
+            ));
+        }
+        self.validate_request(&body)?;
+
+        Ok((prompt, url, body))
+    }
+
+    /// Prints the request that `do_completion` would send without sending it: the body to
+    /// stdout, and the URL and headers to stderr with the API key redacted. Base64 image data
+    /// is shortened so the body stays readable.
+    pub async fn dry_run(&self) -> Result<()> {
+        let (_, url, mut body) = self.prepare_request().await?;
+        self.report(&format!("POST {}", url));
+        for (name, value) in self.request_headers() {
+            let value = match self.config.api_key.is_empty() {
+                true => value,
+                false => value.replace(&self.config.api_key, "<redacted>"),
+            };
+            self.report(&format!("{}: {}", name, value));
+        }
+        shorten_base64(&mut body);
+        println!("{}", serde_json::to_string_pretty(&body)?);
+        Ok(())
+    }
+
+    /// Returns the headers sent with every request: the content type, the authorization the
+    /// API style expects and, unless `no_attribution` is set, OpenRouter's attribution naming
+    /// evocaition or the app given by `referer` and `app_title`, followed by the `header`s given in the configuration.
+    fn request_headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![("Content-Type", "application/json".to_string())];
+        match self.config.api_style {
+            ApiStyle::OpenAi | ApiStyle::Ollama => {
+                headers.push(("Authorization", format!("Bearer {}", self.config.api_key)))
+            }
+            ApiStyle::Anthropic => {
+                headers.push(("x-api-key", self.config.api_key.clone()));
+                headers.push(("anthropic-version", anthropic::API_VERSION.to_string()));
+            }
+        }
+        if !self.config.no_attribution {
+            let referer = self.config.referer.as_deref();
+            let title = self.config.app_title.as_deref();
+            headers.push((
+                "HTTP-Referer",
+                referer
+                    .unwrap_or("https://github.com/tbogdala/evocaition")
+                    .to_string(),
+            ));
+            headers.push(("X-Title", title.unwrap_or("evocaition").to_string()));
+        }
+
+        // the `--header` ones come last, replacing any of the above with the same name
+        let custom: Vec<(String, String)> = self
+            .config
+            .header
+            .iter()
+            .filter_map(|header| header.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        let mut headers: Vec<(String, String)> = headers
+            .into_iter()
+            .filter(|(name, _)| !custom.iter().any(|(c, _)| c.eq_ignore_ascii_case(name)))
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        headers.extend(custom);
+        headers
+    }
+
+    /// Posts the request body to the given URL with the authorization and attribution headers.
+    ///
+    /// The response is returned regardless of its HTTP status so that callers can decide how
+    /// to handle failures.
+    async fn send_request(&self, url: &str, body: &serde_json::Value) -> Result<reqwest::Response> {
+        self.log_traffic(1, &format!("--> POST {}", url));
+        if self.config.verbose >= 2 {
+            let mut logged = body.clone();
+            shorten_base64(&mut logged);
+            self.log_traffic(2, &format!("--> {}", logged));
+        }
+
+        let client = self.http_client().await?;
+        let mut request = client.post(url);
+        for (name, value) in self.request_headers() {
+            request = request.header(name, value);
+        }
+        request
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| self.network_error(e))
+    }
+
+    /// Constructs the request body for an API call based on the provided prompt and configuration.
+    ///
+    /// The function constructs a JSON request body based on the configuration specified in `config`.
+    /// The structure of the request body differs slightly depending on whether plain mode is enabled or not:
+    ///
+    /// - **Plain Mode (self.config.plain = true):**
+    ///   - Includes only the `model`, `prompt`, and `stream` fields.
+    ///
+    /// - **Chat Mode (self.config.plain = false):**
+    ///   - Includes the `model`, `messages`, and `stream` fields.
+    ///   - If image file paths are provided (`self.config.image_file`), the function includes the images
+    ///     in the `messages` array, each as its own content block. If an image provided is a URL, then just
+    ///     the URL is added to the prompt. Otherwise it is assumed to be a filesystem path and the image is
+    ///     read from the file system, converted to base64, and the MIME type is detected from its contents
+    ///     or file extension.
+    ///   - If no image file is provided, only the user's prompt is included in the `messages` array.
+    ///
+    /// Additionally, the function optionally includes other fields (`max_tokens`, `temperature`, `top_k`,
+    /// `top_p`, `min_p`, `repetition_penalty`, `seed`, etc...) in the request body if they are set in the configuration.
+    ///
+    /// # Parameters
+    /// - `prompt` - A string slice representing the user's input prompt to be sent to the model.
+    ///
+    /// # Returns
+    /// A `serde_json::Value` representing the JSON request body to be sent in the API call, or an
+    /// `Err` if an attached image couldn't be read.
+    fn build_request_body(&self, prompt: &str) -> Result<serde_json::Value> {
+        match self.config.api_style {
+            ApiStyle::Anthropic => return self.build_anthropic_request_body(prompt),
+            ApiStyle::Ollama => return self.build_ollama_request_body(prompt),
+            ApiStyle::OpenAi => {}
+        }
+
+        let mut body = if self.config.plain {
+            json!({
+                "model": self.config.model_id,
+                "prompt": prompt,
+                "stream": self.config.stream,
+            })
+        } else {
+            let mut messages: Vec<serde_json::Value> = self
+                .prompt_messages(prompt)?
+                .iter()
+                .map(|message| json!(message))
+                .collect();
+
+            // Handle image inclusion if config.image_file is set; the images are sent
+            // together as their own user message just ahead of the final message.
+            let images = self.image_contents()?;
+            if !images.is_empty() {
+                let content: Vec<serde_json::Value> = images
+                    .into_iter()
+                    .map(|image_content| {
+                        let mut image_url = json!({
+                            "url":  image_content,
+                        });
+                        if let Some(detail) = self.config.image_detail {
+                            image_url["detail"] = json!(detail.as_str());
+                        }
+                        json!({
+                            "type": "image_url",
+                            "image_url": image_url,
+                        })
+                    })
+                    .collect();
+                let image_message = json!({
+                    "role": "user",
+                    "content": content,
+                });
+                messages.insert(messages.len().saturating_sub(1), image_message);
+            }
+
+            json!({
+                "model": self.config.model_id,
+                "messages": messages,
+                "stream": self.config.stream,
+            })
+        };
+
+        // add in some optional parameters to the request
+        if let Some(max_tokens) = self.config.max_tokens {
+            body["max_tokens"] = json!(max_tokens);
+        }
+        if let Some(temp) = self.config.temp {
+            body["temperature"] = json!(temp);
+        }
+        if let Some(top_k) = self.config.top_k {
+            body["top_k"] = json!(top_k);
+        }
+        if let Some(top_p) = self.config.top_p {
+            body["top_p"] = json!(top_p);
+        }
+        if let Some(min_p) = self.config.min_p {
+            body["min_p"] = json!(min_p);
+        }
+        if let Some(rep_pen) = self.config.rep_pen {
+            body["repetition_penalty"] = json!(rep_pen);
+        }
+        if let Some(presence_penalty) = self.config.presence_penalty {
+            body["presence_penalty"] = json!(presence_penalty);
+        }
+        if let Some(frequency_penalty) = self.config.frequency_penalty {
+            body["frequency_penalty"] = json!(frequency_penalty);
+        }
+        if !self.config.stop.is_empty() {
+            body["stop"] = json!(self.config.stop);
+        }
+        if let Some(seed) = self.resolve_seed(prompt) {
+            body["seed"] = json!(seed);
+        }
+        if let Some(n) = self.config.choices {
+            body["n"] = json!(n);
+        }
+        if let Some(reasoning_max_tokens) = self.config.reasoning_max_tokens {
+            body["reasoning"] = json!({"max_tokens": reasoning_max_tokens});
+        }
+        if self.config.json {
+            body["response_format"] = json!({"type": "json_object"});
+        }
+        if let Some(path) = &self.config.json_schema {
+            // a file that's already a `json_schema` object with its name is sent as it is,
+            // while a bare schema is named after the file
+            let schema = config::read_json_schema(path).map_err(|e| anyhow!(e))?;
+            let json_schema = match schema.get("schema") {
+                Some(_) => schema,
+                None => json!({
+                    "name": schema_name(path),
+                    "strict": true,
+                    "schema": schema,
+                }),
+            };
+            body["response_format"] = json!({"type": "json_schema", "json_schema": json_schema});
+        }
+        if let Some(path) = &self.config.tools_file {
+            body["tools"] = config::read_tools_file(path).map_err(|e| anyhow!(e))?;
+        }
+        // anything other than the three modes names the function the model must call
+        if let Some(tool_choice) = &self.config.tool_choice {
+            body["tool_choice"] = match tool_choice.as_str() {
+                "auto" | "none" | "required" => json!(tool_choice),
+                name => json!({"type": "function", "function": {"name": name}}),
+            };
+        }
+
+        // OpenRouter's preferences for which upstream providers serve the request
+        let mut provider = serde_json::Map::new();
+        if !self.config.provider_order.is_empty() {
+            provider.insert("order".to_string(), json!(self.config.provider_order));
+        }
+        if self.config.no_fallbacks {
+            provider.insert("allow_fallbacks".to_string(), json!(false));
+        }
+        if !provider.is_empty() {
+            body["provider"] = serde_json::Value::Object(provider);
+        }
+
+        // ask OpenRouter to include the actual cost in the usage data
+        if self.config.show_cost {
+            body["usage"] = json!({"include": true});
+        }
+
+        Ok(body)
+    }
+
+    /// Returns the conversation to send for the prompt: either the prompt as a single user
+    /// message or, if `parse_roles` is set, the messages parsed from the prompt's transcript.
+    /// Any history the client was given comes before the prompt, and if `context_file` is set,
+    /// its contents are sent first of all as a system message. With `inject_datetime`, a system
+    /// message giving the current date follows any system messages at the start, and with
+    /// `detect_language`, so does one asking for a reply in the prompt's language.
+    fn prompt_messages(&self, prompt: &str) -> Result<Vec<ChatMessage>> {
+        let mut messages = self.history.clone();
+        if self.config.parse_roles {
+            messages.extend(parse_role_transcript(prompt));
+        } else {
+            messages.push(ChatMessage {
+                role: "user".to_string(),
+                content: prompt.to_string(),
+            });
+        }
+
+        if let Some(context_file) = &self.config.context_file {
+            let context =
+                encoding::read_file(context_file, &self.config.input_encoding, "context file")?;
+            messages.insert(
+                0,
+                ChatMessage {
+                    role: "system".to_string(),
+                    content: context,
+                },
+            );
+        }
+
+        if self.config.inject_datetime {
+            // placed after the other system messages so a cached context stays a stable prefix
+            let position = messages.iter().take_while(|m| m.role == "system").count();
+            messages.insert(
+                position,
+                ChatMessage {
+                    role: "system".to_string(),
+                    content: Local::now()
+                        .format(&self.config.datetime_format)
+                        .to_string(),
+                },
+            );
+        }
+
+        #[cfg(feature = "detect-language")]
+        if self.config.detect_language {
+            match whatlang::detect(prompt).filter(|info| info.is_reliable()) {
+                Some(info) => {
+                    let lang = info.lang();
+                    if self.config.verbose > 0 {
+                        self.report(&format!(
+                            "detected language: {} ({})",
+                            lang.eng_name(),
+                            lang.code()
+                        ));
+                    }
+                    let position = messages.iter().take_while(|m| m.role == "system").count();
+                    messages.insert(
+                        position,
+                        ChatMessage {
+                            role: "system".to_string(),
+                            content: self
+                                .config
+                                .language_hint
+                                .replace("{lang}", lang.eng_name())
+                                .replace("{code}", lang.code()),
+                        },
+                    );
+                }
+                None => self.notify(
+                    "Unable to reliably detect the language of the prompt; no language hint was added",
+                ),
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Returns the images to attach to the request, in the order they were given with
+    /// `image_file`; local files and URLs can be mixed.
+    fn image_contents(&self) -> Result<Vec<String>> {
+        self.config
+            .image_file
+            .iter()
+            .map(|image_path| self.image_content(image_path))
+            .collect()
+    }
+
+    /// Returns a single image to attach to the request.
+    ///
+    /// If the image provided is a URL then it's returned as-is. Otherwise it's assumed to be a
+    /// filesystem path and the image is read, converted to base64 and returned as a data URL
+    /// with the MIME type detected from its contents or file extension. Files larger than
+    /// `max_image_bytes` are rejected before they're read so that huge photos can't exhaust memory.
+    fn image_content(&self, image_path: &str) -> Result<String> {
+        let image_content = match Url::parse(image_path) {
+            Ok(_url) => image_path.to_string(),
+            Err(_) => {
+                let image_size = std::fs::metadata(image_path)
+                    .map_err(|e| anyhow!("Failed to read image file '{}': {}", image_path, e))?
+                    .len();
+                if image_size > self.config.max_image_bytes {
+                    return Err(anyhow!(
+                        "The image file '{}' is {} bytes which exceeds the limit of {} bytes set by --max-image-bytes",
+                        image_path,
+                        image_size,
+                        self.config.max_image_bytes
+                    ));
+                }
+
+                // Read the image file
+                let image_data = std::fs::read(image_path)
+                    .map_err(|e| anyhow!("Failed to read image file '{}': {}", image_path, e))?;
+                let mime_type = mime::detect(image_path, &image_data)?;
+                if !mime_type.starts_with("image/") {
+                    return Err(anyhow!(
+                        "The file '{}' is {}, not an image",
+                        image_path,
+                        mime_type
+                    ));
+                }
+
+                // Encode image to base64 directly into the data URL so the encoded
+                // copy is only allocated once
+                let prefix = format!("data:{};base64,", mime_type);
+                let encoded_len = base64::encoded_len(image_data.len(), true).unwrap_or(0);
+                let mut data_url = String::with_capacity(prefix.len() + encoded_len);
+                data_url.push_str(&prefix);
+                BASE64_STANDARD.encode_string(&image_data, &mut data_url);
+                data_url
+            }
+        };
+        Ok(image_content)
+    }
+
+    /// Checks the assembled request body against the configured message limits.
+    ///
+    /// Problems are written to stderr as warnings, unless `strict` is set in which case
+    /// they are returned as an error so the request is never sent. Only the text of each
+    /// message counts toward the character limit; attached images are ignored.
+    ///
+    /// # Parameters
+    /// - `body` - The JSON request body created by `build_request_body`.
+    ///
+    /// # Returns
+    /// - `Ok(())` if the request passed validation or only produced warnings, otherwise an `Err`
+    ///   describing every problem found.
+    fn validate_request(&self, body: &serde_json::Value) -> Result<()> {
+        let messages = match body["messages"].as_array() {
+            Some(messages) => messages,
+            None => return Ok(()),
+        };
+
+        let mut problems = Vec::new();
+        if let Some(max_messages) = self.config.max_messages {
+            if messages.len() > max_messages {
+                problems.push(format!(
+                    "the request has {} messages which exceeds the limit of {}",
+                    messages.len(),
+                    max_messages
+                ));
+            }
+        }
+        if let Some(max_chars) = self.config.max_message_chars {
+            for (i, message) in messages.iter().enumerate() {
+                let chars = message_text_length(message);
+                if chars > max_chars {
+                    problems.push(format!(
+                        "message {} has {} characters which exceeds the limit of {}",
+                        i + 1,
+                        chars,
+                        max_chars
+                    ));
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else if self.config.strict {
+            Err(anyhow!(
+                "Request validation failed: {}",
+                problems.join("; ")
+            ))
+        } else {
+            for problem in problems {
+                self.warn(&problem);
+            }
+            Ok(())
+        }
+    }
+
+    /// Warns about sampling parameters in the request body that the model doesn't support,
+    /// since the provider would otherwise drop them silently.
+    ///
+    /// The supported parameters are only known for OpenRouter, so the check is skipped for
+    /// other endpoints or if the lookup fails. The lookup is cached for the life of the client.
+    /// If `strict` is set, unsupported parameters are an error instead of a warning.
+    ///
+    /// # Parameters
+    /// - `body` - The JSON request body created by `build_request_body`.
+    async fn check_supported_parameters(&self, body: &serde_json::Value) -> Result<()> {
+        // maps the request body fields to the command line flags that set them
+        const SAMPLING_PARAMETERS: [(&str, &str); 13] = [
+            ("temperature", "--temp"),
+            ("top_p", "--top-p"),
+            ("top_k", "--top-k"),
+            ("min_p", "--min-p"),
+            ("repetition_penalty", "--rep-pen"),
+            ("presence_penalty", "--presence-penalty"),
+            ("frequency_penalty", "--frequency-penalty"),
+            ("stop", "--stop"),
+            ("seed", "--seed"),
+            ("reasoning", "--reasoning-max-tokens"),
+            ("response_format", "--json/--json-schema"),
+            ("tools", "--tools-file"),
+            ("tool_choice", "--tool-choice"),
+        ];
+
+        if self.config.api_style != ApiStyle::OpenAi || !self.is_openrouter() {
+            return Ok(());
+        }
+        let requested: Vec<_> = SAMPLING_PARAMETERS
+            .iter()
+            .filter(|(field, _)| body.get(*field).is_some())
+            .collect();
+        if requested.is_empty() {
+            return Ok(());
+        }
+
+        let parameters = self
+            .model_parameters
+            .get_or_init(|| async { self.fetch_model_parameters().await.ok() })
+            .await;
+        let Some(parameters) = parameters else {
+            return Ok(());
+        };
+
+        let unsupported: Vec<&str> = requested
+            .iter()
+            .filter(|(field, _)| !parameters.supported_parameters.iter().any(|p| p == field))
+            .map(|(_, flag)| *flag)
+            .collect();
+        if unsupported.is_empty() {
+            Ok(())
+        } else if self.config.strict {
+            Err(anyhow!(
+                "'{}' does not support: {}",
+                self.config.model_id,
+                unsupported.join(", ")
+            ))
+        } else {
+            self.warn(&format!(
+                "'{}' does not support {}; the provider will ignore it",
+                self.config.model_id,
+                unsupported.join(", ")
+            ));
+            Ok(())
+        }
+    }
+
+    /// Returns true if the configured endpoint is OpenRouter.
+    fn is_openrouter(&self) -> bool {
+        Url::parse(&self.config.api)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.ends_with("openrouter.ai")))
+            .unwrap_or(false)
+    }
+
+    /// Checks that `model_id` is in the models list if `verify_model` is set, so that a typo
+    /// is caught before the request is sent, along with the most similar model IDs.
+    ///
+    /// The check is skipped for endpoints other than OpenRouter, whose models lists can't be
+    /// relied upon, or if the list can't be fetched. The list is cached for the life of the
+    /// client.
+    async fn verify_model(&self) -> Result<()> {
+        // how many similar model IDs are suggested
+        const SUGGESTIONS: usize = 3;
+
+        if !self.config.verify_model || !self.is_openrouter() {
+            return Ok(());
+        }
+        let models = self
+            .models
+            .get_or_init(|| async { self.list_models().await.ok() })
+            .await;
+        let Some(models) = models else {
+            self.notify("Unable to fetch the models list; skipping --verify-model");
+            return Ok(());
+        };
+        if models.iter().any(|m| m.id == self.config.model_id) {
+            return Ok(());
+        }
+
+        let mut closest: Vec<(usize, &str)> = models
+            .iter()
+            .map(|m| (edit_distance(&self.config.model_id, &m.id), m.id.as_str()))
+            .collect();
+        closest.sort();
+        let suggestions: Vec<&str> = closest
+            .iter()
+            .take(SUGGESTIONS)
+            .map(|(_, id)| *id)
+            .collect();
+        Err(anyhow!(
+            "The model '{}' isn't in the models list. Did you mean: {}?",
+            self.config.model_id,
+            suggestions.join(", ")
+        ))
+    }
+
+    /// Returns the seed to send with the request, if any.
+    ///
+    /// When `seed_from_prompt` is set the seed is derived from the prompt text,
+    /// otherwise the explicitly configured seed is used.
+    fn resolve_seed(&self, prompt: &str) -> Option<i64> {
+        if self.config.seed_from_prompt {
+            Some(seed_from_prompt(prompt))
+        } else {
+            self.config.seed
+        }
+    }
+
+    /// Processes a streaming HTTP response, handling JSON data chunks and invoking callbacks for each message.
+    ///
+    /// This function asynchronously reads chunks from a `reqwest::Response` object, decodes them from UTF-8,
+    /// and processes lines that start with the prefix "data: ". Each valid JSON message is parsed into a `Response`
     /// object, and the appropriate callback is invoked based on the type of choice contained within the response.
     ///
     /// # Parameters
@@ -334,81 +1898,397 @@ impl ApiClient {
     ///   should already have been sent.
     ///
     /// # Returns
-    /// - Returns `Ok(())` if the response was processed successfully, or an `Err` if an error
-    ///   occurred during processing.
-    ///
-    /// # Notes
-    /// - The buffer is trimmed to remove leading and trailing whitespace after processing each line.
-    async fn process_streaming_response(&self, mut response: reqwest::Response) -> Result<()> {
-        let mut buffer = String::new();
+    /// - Returns the accumulated `Completion` if the response was processed successfully, or an `Err`
+    ///   if an error occurred during processing. If `save_on_error` is configured, the text received
+    ///   before the error is written to that file along with the error.
+    async fn process_streaming_response(&self, response: reqwest::Response) -> Result<Completion> {
+        let mut completion = Completion::default();
+        let result = self
+            .read_streaming_response(response, &mut completion)
+            .await
+            .and_then(|()| self.flush_tool_calls(&mut completion).map_err(Into::into))
+            .and_then(|()| self.flush_choices(&mut completion).map_err(Into::into));
+
+        // whatever text is still being gathered for `chunk_size` or `timestamps` goes out once
+        // the stream ends
+        let chunk = std::mem::take(&mut *self.chunk.lock().unwrap());
+        let mut result = match (&self.callback, chunk.is_empty()) {
+            (Callback::Delta(callback), false) => result.and_then(|()| {
+                let text = match self.config.timestamps {
+                    true => timestamp_line(&chunk),
+                    false => chunk,
+                };
+                match self.config.min_interval {
+                    Some(_) => {
+                        self.pacing.lock().unwrap().pending.push_str(&text);
+                        Ok(())
+                    }
+                    None => self
+                        .write_out(&mut callback.lock().unwrap(), &text)
+                        .map_err(Into::into),
+                }
+            }),
+            _ => result,
+        };
+
+        // the text still held back by `min_interval` waits out the interval, unless the stream
+        // failed, in which case it goes out right away alongside the error
+        if let Some(due) = self.pacing_due() {
+            if result.is_ok() {
+                tokio::time::sleep_until(due.into()).await;
+            }
+            let flushed = self.flush_pacing();
+            result = result.and(flushed.map_err(Into::into));
+        }
+
+        match result {
+            Ok(()) => Ok(completion),
+            Err(e) => {
+                self.finish_cut_off_response(&completion, &e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Wraps up a streamed response that ended in an error. What arrived before the error
+    /// stays on screen, finished off and marked so that the error isn't mistaken for part of
+    /// the response, and is saved along with the error if `save_on_error` is configured.
+    fn finish_cut_off_response(&self, completion: &Completion, e: &anyhow::Error) {
+        if !completion.content.is_empty() && !error::is_broken_pipe(e) {
+            if let Callback::Delta(callback) = &self.callback {
+                if *self.line_open.lock().unwrap() {
+                    let _ = self.write_out(&mut callback.lock().unwrap(), "\n");
+                }
+            }
+            self.notify("(the response was cut off by an error)");
+        }
+        if let Some(path) = &self.config.save_on_error {
+            let saved = format!("{}\n\nERROR: {}\n", completion.content, e);
+            match std::fs::write(path, saved) {
+                Ok(()) => self.notify(&format!("Partial output saved to {}", path)),
+                Err(save_err) => self.warn(&format!(
+                    "unable to save the partial output to {}: {}",
+                    path, save_err
+                )),
+            }
+        }
+    }
+
+    /// Reads the chunks of a streaming HTTP response, accumulating the text, finish reason and
+    /// usage into `completion` as they arrive.
+    ///
+    /// Because `completion` is owned by the caller, everything received before an error is
+    /// still available to it when an `Err` is returned.
+    ///
+    /// # Notes
+    /// - The buffer is trimmed to remove leading and trailing whitespace after processing each line.
+    async fn read_streaming_response(
+        &self,
+        mut response: reqwest::Response,
+        completion: &mut Completion,
+    ) -> Result<()> {
+        let mut buffer = String::new();
+        // a multibyte character can be split between chunks, so any incomplete bytes at the
+        // end of a chunk wait here for the rest of the character
+        let mut undecoded = Vec::new();
+
+        loop {
+            // text held back by `min_interval` is written once it's due, even if nothing more
+            // has arrived by then
+            let next = match self.pacing_due() {
+                Some(due) => tokio::select! {
+                    next = response.chunk() => next,
+                    _ = tokio::time::sleep_until(due.into()) => {
+                        self.flush_pacing()?;
+                        continue;
+                    }
+                },
+                None => response.chunk().await,
+            };
+            let Some(chunk) = next.map_err(|e| self.network_error(e))? else {
+                break;
+            };
+            undecoded.extend_from_slice(&chunk);
+            buffer.push_str(&decode_complete_utf8(&mut undecoded));
+            if self.process_stream_lines(&mut buffer, completion)? {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles every complete line in `buffer`, removing each one before it's looked at and
+    /// leaving a partial line at the end for the next chunk to finish.
+    ///
+    /// # Returns
+    /// - `Ok(true)` once the `[DONE]` line that ends the stream is reached, `Ok(false)` if more
+    ///   is expected, or an `Err` if a line held an error or couldn't be parsed.
+    fn process_stream_lines(
+        &self,
+        buffer: &mut String,
+        completion: &mut Completion,
+    ) -> Result<bool> {
+        while let Some(line) = take_line(buffer) {
+            let kind = classify_stream_line(&line, self.config.api_style);
+            if kind == StreamLine::Blank {
+                continue;
+            }
+            self.log_traffic(2, &format!("<-- {}", line));
+
+            match kind {
+                StreamLine::Done => return Ok(true),
+                StreamLine::Data(json_str) => match self.config.api_style {
+                    ApiStyle::OpenAi => self.process_stream_data(json_str, completion)?,
+                    ApiStyle::Anthropic => {
+                        self.process_anthropic_stream_data(json_str, completion)?
+                    }
+                    ApiStyle::Ollama => self.process_ollama_stream_data(json_str, completion)?,
+                },
+                // comments only hold the connection open, and lines like Anthropic's `event:`
+                // aren't needed since the data names its own type
+                StreamLine::Blank | StreamLine::Comment | StreamLine::Other => {}
+            }
+        }
+        Ok(false)
+    }
+
+    /// Processes the JSON payload of a single `data: ` line from an OpenAI compatible stream.
+    ///
+    /// Each choice's text is appended to `completion` and reported to the callback. When
+    /// `choices` asks for several, the first is written under its header as it arrives while
+    /// the others are held back for `flush_choices`. The usage arrives at the end of the
+    /// stream accompanied by an empty choices array.
+    ///
+    /// # Returns
+    /// - `Ok(())` if the payload was handled, or an `Err` if it was an error object or couldn't be parsed.
+    fn process_stream_data(&self, json_str: &str, completion: &mut Completion) -> Result<()> {
+        match serde_json::from_str::<Response>(json_str) {
+            Ok(response) => {
+                completion.id = response.id.or(completion.id.take());
+                completion.model = Some(response.model);
+                completion.created = Some(response.created);
+                for choice in response.choices {
+                    let (index, content, reasoning, tool_calls, finish_reason) = match choice {
+                        Choice::NonChat(c) => (c.index, Some(c.text), None, None, c.finish_reason),
+                        Choice::Streaming(c) => (
+                            c.index,
+                            c.delta.content,
+                            c.delta.reasoning,
+                            c.delta.tool_calls,
+                            c.finish_reason,
+                        ),
+                        Choice::NonStreaming(c) => (
+                            c.index,
+                            c.message.content,
+                            c.message.reasoning,
+                            c.message.tool_calls,
+                            c.finish_reason,
+                        ),
+                    };
+                    // tool calls arrive in pieces, so they're put together and written out
+                    // once the stream ends
+                    let mut parts = self.tool_calls.lock().unwrap();
+                    for call in tool_calls.unwrap_or_default() {
+                        parts.entry((index, call.index)).or_default().push(&call);
+                    }
+                    drop(parts);
+                    // the reasoning of the other choices would only be jumbled together with it
+                    if index == 0 {
+                        self.emit_reasoning(reasoning.as_deref().unwrap_or_default());
+                    }
+                    let delta = content.unwrap_or_default();
+                    if self.config.choices.unwrap_or(1) > 1 {
+                        if index > 0 {
+                            let mut choices = self.choices.lock().unwrap();
+                            choices.entry(index).or_default().push_str(&delta);
+                            continue;
+                        }
+                        if completion.content.is_empty() {
+                            self.emit_choice_header(0, completion)?;
+                        }
+                    }
+                    completion.content.push_str(&delta);
+                    self.emit(StreamEvent {
+                        delta: &delta,
+                        cumulative: &completion.content,
+                        model: completion.model.as_deref(),
+                        finish_reason: finish_reason.as_deref(),
+                        usage: None,
+                    })?;
+                    if finish_reason.is_some() {
+                        completion.finish_reason = finish_reason;
+                    }
+                }
+
+                // the usage arrives at the end accompanied by an empty choices array
+                if let Some(usage) = &response.usage {
+                    self.emit(StreamEvent {
+                        delta: "",
+                        cumulative: &completion.content,
+                        model: completion.model.as_deref(),
+                        finish_reason: None,
+                        usage: Some(usage),
+                    })?;
+                    completion.usage = Some(usage.clone());
+                }
+                Ok(())
+            }
+            Err(_) => match serde_json::from_str::<ErrorResponseContainer>(json_str) {
+                Ok(error_container) => Err(error_container.error.into_error()),
+                Err(e) => Err(anyhow!(
+                    "Failed to parse JSON: {}\nRaw JSON: {}",
+                    e,
+                    json_str
+                )),
+            },
+        }
+    }
+
+    /// Writes the `--- choice N ---` header that comes ahead of the choice with the given
+    /// zero-based `index` when `choices` asks for several, starting a new line first if needed.
+    fn emit_choice_header(&self, index: usize, completion: &mut Completion) -> io::Result<()> {
+        let mut header = format!("--- choice {} ---\n", index + 1);
+        if !completion.content.is_empty() && !completion.content.ends_with('\n') {
+            header.insert(0, '\n');
+        }
+        completion.content.push_str(&header);
+        self.emit(StreamEvent {
+            delta: &header,
+            cumulative: &completion.content,
+            model: completion.model.as_deref(),
+            finish_reason: None,
+            usage: None,
+        })
+    }
+
+    /// Writes out the tool calls that were put together while the stream was read, each as a
+    /// line of JSON. Those of the first choice follow its text, while those of the others are
+    /// added to the text held back for them.
+    fn flush_tool_calls(&self, completion: &mut Completion) -> io::Result<()> {
+        let tool_calls = std::mem::take(&mut *self.tool_calls.lock().unwrap());
+        for ((index, _), call) in tool_calls {
+            if index > 0 {
+                let mut choices = self.choices.lock().unwrap();
+                choices
+                    .entry(index)
+                    .or_default()
+                    .push_str(&call.to_json_line());
+                continue;
+            }
+            if self.config.choices.unwrap_or(1) > 1 && completion.content.is_empty() {
+                self.emit_choice_header(0, completion)?;
+            }
+            self.emit_tool_call(&call, completion)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a tool call the model made as a line of JSON, starting a new line first if
+    /// needed.
+    fn emit_tool_call(&self, call: &ToolCallParts, completion: &mut Completion) -> io::Result<()> {
+        let mut line = call.to_json_line();
+        if !completion.content.is_empty() && !completion.content.ends_with('\n') {
+            line.insert(0, '\n');
+        }
+        completion.content.push_str(&line);
+        self.emit(StreamEvent {
+            delta: &line,
+            cumulative: &completion.content,
+            model: completion.model.as_deref(),
+            finish_reason: None,
+            usage: None,
+        })
+    }
+
+    /// Writes out the choices after the first that were held back while the stream was read,
+    /// each under its own header and in the order of their indexes.
+    fn flush_choices(&self, completion: &mut Completion) -> io::Result<()> {
+        let choices = std::mem::take(&mut *self.choices.lock().unwrap());
+        for (index, content) in choices {
+            self.emit_choice_header(index, completion)?;
+            completion.content.push_str(&content);
+            self.emit(StreamEvent {
+                delta: &content,
+                cumulative: &completion.content,
+                model: completion.model.as_deref(),
+                finish_reason: None,
+                usage: None,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Writes the bytes of a streaming HTTP response directly to stdout as they are received.
+    ///
+    /// No parsing is done at all, so the SSE framing (`data:` prefixes, blank lines, `[DONE]`)
+    /// is preserved exactly as the API sent it. The callback is not invoked.
+    ///
+    /// # Parameters
+    /// - `response`: A mutable `reqwest::Response` object representing the incoming HTTP response which
+    ///   should already have been sent.
+    ///
+    /// # Returns
+    /// - Returns `Ok(())` if the whole response was relayed, or an `Err` if reading the response or
+    ///   writing to stdout failed.
+    async fn passthrough_streaming_response(&self, mut response: reqwest::Response) -> Result<()> {
+        let mut stdout = io::stdout();
+        while let Some(chunk) = response.chunk().await.map_err(|e| self.network_error(e))? {
+            stdout.write_all(&chunk)?;
+            stdout.flush()?;
+        }
 
-        while let Ok(Some(chunk)) = response.chunk().await {
-            buffer.push_str(&String::from_utf8_lossy(&chunk));
+        Ok(())
+    }
 
-            // Process complete lines from the buffer
-            while let Some(pos) = buffer.find('\n') {
-                let line = buffer[..pos].trim();
+    /// Writes the response to stdout without parsing it, so that shapes the response structs
+    /// don't model can still be seen. A non-streaming response is written as it is, while for
+    /// a stream the data of each event is written on its own line as it arrives, leaving out
+    /// the SSE framing, comments and the closing `[DONE]`. The callback is not invoked.
+    ///
+    /// # Parameters
+    /// - `response`: A mutable `reqwest::Response` object representing the incoming HTTP response which
+    ///   should already have been sent.
+    ///
+    /// # Returns
+    /// - Returns `Ok(())` if the whole response was written, or an `Err` if reading the response or
+    ///   writing to stdout failed.
+    async fn raw_response(&self, mut response: reqwest::Response) -> Result<()> {
+        let mut stdout = io::stdout();
+        if !self.config.stream {
+            let response_text = response.text().await.map_err(|e| self.network_error(e))?;
+            writeln!(stdout, "{}", response_text.trim_end())?;
+            return Ok(());
+        }
 
-                // Skip empty lines
-                if line.is_empty() {
-                    continue;
+        let mut buffer = String::new();
+        let mut undecoded = Vec::new();
+        loop {
+            let chunk = response.chunk().await.map_err(|e| self.network_error(e))?;
+            let finished = chunk.is_none();
+            match chunk {
+                Some(chunk) => {
+                    undecoded.extend_from_slice(&chunk);
+                    buffer.push_str(&decode_complete_utf8(&mut undecoded));
                 }
+                // a last line without a newline is still written once the stream ends
+                None => buffer.push('\n'),
+            }
 
-                // Check if line starts with "data: " and parse the JSON
-                if let Some(json_str) = line.strip_prefix("data: ") {
-                    if json_str.trim() == "[DONE]" {
-                        break;
-                    }
-
-                    match serde_json::from_str::<Response>(json_str) {
-                        Ok(response) => {
-                            for choice in response.choices {
-                                match choice {
-                                    Choice::NonChat(c) => {
-                                        (self.callback)(&c.text);
-                                    }
-                                    Choice::Streaming(c) => {
-                                        if let Some(content) = c.delta.content {
-                                            (self.callback)(&content);
-                                        }
-                                    }
-                                    Choice::NonStreaming(c) => {
-                                        if let Some(content) = c.message.content {
-                                            (self.callback)(&content);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        Err(_) => match serde_json::from_str::<ErrorResponseContainer>(json_str) {
-                            Ok(error_contaner) => {
-                                return Err(anyhow::Error::msg(format!(
-                                    "API request failed with code {}: {}\nError metadata:{:?}",
-                                    error_contaner.error.code,
-                                    error_contaner.error.message,
-                                    error_contaner.error.metadata,
-                                )));
-                            }
-                            Err(e) => {
-                                return Err(anyhow!(
-                                    "Failed to parse JSON: {}\nRaw JSON: {}",
-                                    e,
-                                    json_str
-                                ));
-                            }
-                        },
+            while let Some(line) = take_line(&mut buffer) {
+                match classify_stream_line(&line, self.config.api_style) {
+                    StreamLine::Data(data) => {
+                        writeln!(stdout, "{}", data)?;
+                        stdout.flush()?;
                     }
+                    StreamLine::Done => return Ok(()),
+                    StreamLine::Blank | StreamLine::Comment | StreamLine::Other => {}
                 }
-
-                // if the line didn't start with 'Data: ' then we just throw it away
-                // and trim it out of the buffer...
-
-                buffer = buffer[pos + 1..].to_string();
-                buffer = buffer.trim_start().to_string();
+            }
+            if finished {
+                return Ok(());
             }
         }
-
-        Ok(())
     }
 
     /// Processes a non-streaming JSON response from an API.
@@ -420,41 +2300,973 @@ impl ApiClient {
     /// - `response_text`: A string slice containing the JSON response text from the API.
     ///
     /// # Returns
-    /// - An empty `Result` indicating success or an Err indicating failure.
-    fn process_non_streaming_response(&self, response_text: &str) -> Result<()> {
-        match serde_json::from_str::<Response>(&response_text) {
+    /// - The `Completion` indicating success or an Err indicating failure.
+    fn process_non_streaming_response(&self, response_text: &str) -> Result<Completion> {
+        match self.config.api_style {
+            ApiStyle::Anthropic => return self.process_anthropic_response(response_text),
+            ApiStyle::Ollama => return self.process_ollama_response(response_text),
+            ApiStyle::OpenAi => {}
+        }
+
+        match serde_json::from_str::<Response>(response_text) {
             Ok(api_result) => {
-                if let Some(choice) = api_result.choices.first() {
-                    match choice {
-                        Choice::NonChat(ncc) => (self.callback)(&ncc.text),
+                let mut completion = Completion {
+                    id: api_result.id.clone(),
+                    model: Some(api_result.model.clone()),
+                    created: Some(api_result.created),
+                    usage: api_result.usage.clone(),
+                    ..Default::default()
+                };
+                let labeled = self.config.choices.unwrap_or(1) > 1;
+                for (position, choice) in api_result.choices.iter().enumerate() {
+                    let (index, content, finish_reason) = match choice {
+                        Choice::NonChat(ncc) => (ncc.index, ncc.text.clone(), &ncc.finish_reason),
                         Choice::NonStreaming(nsc) => {
-                            (self.callback)(&nsc.message.content.clone().unwrap_or_default())
+                            if let Some(reasoning) = &nsc.message.reasoning {
+                                self.emit_reasoning(reasoning);
+                                self.emit_reasoning("\n");
+                            }
+                            // any tool calls the model made follow its text, a line of JSON each
+                            let mut content = nsc.message.content.clone().unwrap_or_default();
+                            for call in nsc.message.tool_calls.iter().flatten() {
+                                let mut parts = ToolCallParts::default();
+                                parts.push(call);
+                                if !content.is_empty() && !content.ends_with('\n') {
+                                    content.push('\n');
+                                }
+                                content.push_str(&parts.to_json_line());
+                            }
+                            (nsc.index, content, &nsc.finish_reason)
                         }
                         Choice::Streaming(_) => {
                             panic!("Shouldn't be getting streaming responses here...")
                         }
+                    };
+                    if labeled {
+                        self.emit_choice_header(index, &mut completion)?;
+                    }
+                    completion.content.push_str(&content);
+                    // the usage covers the whole response, so it's only reported once
+                    let last = position + 1 == api_result.choices.len();
+                    self.emit(StreamEvent {
+                        delta: &content,
+                        cumulative: &completion.content,
+                        model: completion.model.as_deref(),
+                        finish_reason: finish_reason.as_deref(),
+                        usage: api_result.usage.as_ref().filter(|_| last),
+                    })?;
+                    if completion.finish_reason.is_none() {
+                        completion.finish_reason = finish_reason.clone();
                     }
                 }
+                Ok(completion)
+            }
+            Err(parse_error) => match serde_json::from_str::<ErrorResponseContainer>(response_text)
+            {
+                Ok(error_container) => Err(error_container.error.into_error()),
+                // running out of input means the body was cut off rather than malformed
+                Err(_) if parse_error.is_eof() => Err(anyhow!(
+                    "The response appears to have been truncated after {} bytes; the connection or a proxy may have cut it off: {}\nRaw JSON: {}",
+                    response_text.len(),
+                    parse_error,
+                    response_text
+                )),
+                Err(e) => Err(anyhow!(
+                    "Failed to parse JSON: {}\nRaw JSON: {}",
+                    e,
+                    response_text
+                )),
+            },
+        }
+    }
+
+    /// Applies `--strip-code-fences` and then `--trim` or `--no-trailing-newline` to the full
+    /// text of a response.
+    fn shape_content(&self, content: &str) -> String {
+        let stripped;
+        let content = match self.config.strip_code_fences {
+            true => {
+                stripped = strip_code_fences(content);
+                stripped.as_str()
+            }
+            false => content,
+        };
+        if self.config.trim {
+            content.trim().to_string()
+        } else if self.config.no_trailing_newline {
+            content.trim_end_matches(['\n', '\r']).to_string()
+        } else {
+            content.to_string()
+        }
+    }
+
+    /// Writes a message about the traffic with the API to stderr if `verbose` was given at
+    /// least `level` times: once for the URLs and statuses, twice for the bodies and the
+    /// raw lines of streamed responses. The headers, and with them the API key, are never
+    /// logged.
+    fn log_traffic(&self, level: u8, message: &str) {
+        if self.config.verbose >= level {
+            self.report(message);
+        }
+    }
+
+    /// Writes an informational message to stderr unless `quiet` is set.
+    fn notify(&self, message: &str) {
+        if !self.config.quiet {
+            self.report(message);
+        }
+    }
+
+    /// Writes a diagnostic, like the token usage, to stderr in its own color if `color` allows.
+    fn report(&self, message: &str) {
+        let message = style::paint(message, Style::Diagnostic, self.config.use_color());
+        let _ = writeln!(self.diagnostics.lock().unwrap(), "{}", message);
+    }
+
+    /// Writes a warning to stderr, colored if `color` allows.
+    fn warn(&self, message: &str) {
+        let message = format!("WARNING: {}", message);
+        let message = style::paint(&message, Style::Warning, self.config.use_color());
+        let _ = writeln!(self.diagnostics.lock().unwrap(), "{}", message);
+    }
+
+    /// Writes reasoning text from the model to stderr if `show_reasoning` is configured,
+    /// keeping it out of the completion text sent to the callback.
+    fn emit_reasoning(&self, reasoning: &str) {
+        if self.config.show_reasoning && !reasoning.is_empty() {
+            eprint!("{}", reasoning);
+            let _ = io::stderr().flush();
+        }
+    }
+
+    /// Returns when the text held back by `min_interval` is due to be written, or `None` if
+    /// there isn't any.
+    fn pacing_due(&self) -> Option<Instant> {
+        let interval = Duration::from_millis(self.config.min_interval?);
+        let pacing = self.pacing.lock().unwrap();
+        if pacing.pending.is_empty() {
+            return None;
+        }
+        Some(match pacing.last_write {
+            Some(last_write) => last_write + interval,
+            None => Instant::now(),
+        })
+    }
+
+    /// Writes out all of the text held back by `min_interval` in one call to the callback.
+    fn flush_pacing(&self) -> io::Result<()> {
+        let mut pacing = self.pacing.lock().unwrap();
+        if pacing.pending.is_empty() {
+            return Ok(());
+        }
+        let text = std::mem::take(&mut pacing.pending);
+        pacing.last_write = Some(Instant::now());
+        match &self.callback {
+            Callback::Delta(callback) => self.write_out(&mut callback.lock().unwrap(), &text),
+            Callback::Event(_) => Ok(()),
+        }
+    }
+
+    /// Writes text out through the delta callback, noting whether it leaves a line unfinished.
+    fn write_out(&self, callback: &mut ApiClientCallback, text: &str) -> io::Result<()> {
+        callback(text)?;
+        if let Some(last) = text.chars().last() {
+            *self.line_open.lock().unwrap() = last != '\n';
+        }
+        Ok(())
+    }
+
+    /// Reports an update to the callback supplied when the `ApiClient` was created.
+    ///
+    /// Plain delta callbacks only receive non-empty text, while event callbacks receive every
+    /// event that carries text, a finish reason or usage data.
+    ///
+    /// # Returns
+    /// - An `Err` if the delta callback failed to write out the text, such as when stdout was
+    ///   closed early by the program reading it.
+    fn emit(&self, event: StreamEvent) -> io::Result<()> {
+        if self.config.token_stats && !event.delta.is_empty() {
+            self.timings.lock().unwrap().deltas.push(Instant::now());
+        }
+        if self.config.show_empty_deltas && event.finish_reason.is_none() && event.usage.is_none() {
+            let mut counts = self.delta_counts.lock().unwrap();
+            counts.total += 1;
+            if event.delta.trim().is_empty() {
+                counts.empty += 1;
             }
-            Err(_) => match serde_json::from_str::<ErrorResponseContainer>(&response_text) {
-                Ok(error_container) => {
-                    return Err(anyhow::Error::msg(format!(
-                        "API request failed with code {}: {}\nError metadata:{:?}",
-                        error_container.error.code,
-                        error_container.error.message,
-                        error_container.error.metadata,
-                    )));
+        }
+
+        match &self.callback {
+            Callback::Delta(callback) => {
+                let mut delta_callback = callback.lock().unwrap();
+                // with `min_interval`, text is gathered here and written out as it comes due
+                // while the stream is read
+                let mut callback = |text: &str| match self.config.min_interval {
+                    Some(_) => {
+                        self.pacing.lock().unwrap().pending.push_str(text);
+                        Ok(())
+                    }
+                    None => self.write_out(&mut delta_callback, text),
+                };
+                // the whole response arrives as one delta since this can't be used when streaming
+                let stripped;
+                let delta = match self.config.strip_code_fences {
+                    true => {
+                        stripped = strip_code_fences(event.delta);
+                        stripped.as_str()
+                    }
+                    false => event.delta,
+                };
+                let text = self.shaper.lock().unwrap().shape(
+                    delta,
+                    self.config.trim,
+                    self.config.no_trailing_newline,
+                );
+                if text.is_empty() {
+                    return Ok(());
                 }
-                Err(e) => {
-                    return Err(anyhow!(
-                        "Failed to parse JSON: {}\nRaw JSON: {}",
-                        e,
-                        response_text
-                    ));
+                let mut model_echoed = self.model_echoed.lock().unwrap();
+                let text = match self.config.echo_model_in_output && !*model_echoed {
+                    true => {
+                        *model_echoed = true;
+                        let model = event.model.unwrap_or(&self.config.model_id);
+                        format!("[{}]\n{}", model, text)
+                    }
+                    false => text,
+                };
+                if self.config.timestamps {
+                    // only whole lines go out so that each is stamped when it was finished
+                    let mut chunk = self.chunk.lock().unwrap();
+                    chunk.push_str(&text);
+                    while let Some(end) = chunk.find('\n') {
+                        let line: String = chunk.drain(..=end).collect();
+                        callback(&timestamp_line(&line))?;
+                    }
+                    return Ok(());
+                }
+                match self.config.chunk_size {
+                    Some(chunk_size) => {
+                        let mut chunk = self.chunk.lock().unwrap();
+                        chunk.push_str(&text);
+                        if chunk.contains('\n') || chunk.chars().count() >= chunk_size {
+                            callback(&chunk)?;
+                            chunk.clear();
+                        }
+                        Ok(())
+                    }
+                    None => callback(&text),
+                }
+            }
+            Callback::Event(callback) => {
+                if !event.delta.is_empty() || event.finish_reason.is_some() || event.usage.is_some()
+                {
+                    (callback.lock().unwrap())(&event);
                 }
+                Ok(())
+            }
+        }
+    }
+}
+
+// A line of a streamed response, by what it carries.
+#[derive(Debug, PartialEq, Eq)]
+enum StreamLine<'a> {
+    // The blank line that separates SSE events
+    Blank,
+
+    // An SSE comment, like OpenRouter's `: OPENROUTER PROCESSING` keep-alives, which only
+    // holds the connection open
+    Comment,
+
+    // The JSON of a `data:` line, or a whole line of Ollama's newline-delimited JSON
+    Data(&'a str),
+
+    // The `[DONE]` that ends an OpenAI style stream
+    Done,
+
+    // Anything else, like the `event:` lines of Anthropic's stream
+    Other,
+}
+
+/// Removes the next complete line from the front of `buffer` and returns it trimmed, leaving
+/// a partial line at the end in place until the rest of it arrives.
+fn take_line(buffer: &mut String) -> Option<String> {
+    let end = buffer.find('\n')?;
+    let line: String = buffer.drain(..=end).collect();
+    Some(line.trim().to_string())
+}
+
+/// Works out what a trimmed line of a streamed response carries. Ollama streams
+/// newline-delimited JSON, so each of its lines is data, while the other API styles send
+/// server-sent events.
+fn classify_stream_line(line: &str, api_style: ApiStyle) -> StreamLine<'_> {
+    if line.is_empty() {
+        return StreamLine::Blank;
+    }
+    if line.starts_with(':') {
+        return StreamLine::Comment;
+    }
+    if api_style == ApiStyle::Ollama {
+        return StreamLine::Data(line);
+    }
+    match line.strip_prefix("data:").map(str::trim_start) {
+        Some("[DONE]") => StreamLine::Done,
+        Some(data) => StreamLine::Data(data),
+        None => StreamLine::Other,
+    }
+}
+
+/// Decodes the complete UTF-8 characters at the start of `bytes`, removing them and leaving
+/// behind any character that's cut off at the end so it can be finished by the next chunk.
+/// Invalid bytes are replaced with `U+FFFD`, since more input won't fix them.
+fn decode_complete_utf8(bytes: &mut Vec<u8>) -> String {
+    // invalid bytes are stepped over so that a character cut off after them is still found
+    let mut start = 0;
+    let complete = loop {
+        match std::str::from_utf8(&bytes[start..]) {
+            Ok(_) => break bytes.len(),
+            Err(e) => match e.error_len() {
+                Some(invalid) => start += e.valid_up_to() + invalid,
+                None => break start + e.valid_up_to(),
             },
         }
+    };
+    let decoded = String::from_utf8_lossy(&bytes[..complete]).into_owned();
+    bytes.drain(..complete);
+    decoded
+}
 
-        Ok(())
+/// Prefixes a line of streamed text with the current time as an ISO-8601 timestamp.
+fn timestamp_line(line: &str) -> String {
+    format!(
+        "{} {}",
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        line
+    )
+}
+
+/// Reads the body of a failed response so it can be reported to the user.
+async fn read_error_body(response: reqwest::Response) -> String {
+    response
+        .text()
+        .await
+        .unwrap_or_else(|_| "Unable to read response body".to_string())
+}
+
+/// Returns true for the statuses that usually mean the request will succeed if sent again.
+fn is_retryable_status(status: reqwest::StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 500 | 502 | 503)
+}
+
+/// Counts the single character insertions, deletions and substitutions needed to turn `a`
+/// into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Reads how long a `Retry-After` header asks to wait, given either as a number of seconds
+/// or as an HTTP date.
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    match value.parse::<f64>() {
+        Ok(seconds) => Duration::try_from_secs_f64(seconds).ok(),
+        Err(_) => {
+            let date = DateTime::parse_from_rfc2822(value).ok()?;
+            Some(
+                SystemTime::from(date)
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default(),
+            )
+        }
+    }
+}
+
+/// Adds a random amount of up to half of `delay` to it, so that clients that failed at the
+/// same time don't all retry at the same time too.
+fn with_jitter(delay: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.subsec_nanos())
+        .unwrap_or_default();
+    delay + delay.mul_f64(f64::from(nanos % 1000) / 2000.0)
+}
+
+/// Builds the error returned when the API responds with a non-success status.
+fn status_error(status: reqwest::StatusCode, error_text: &str) -> anyhow::Error {
+    let message = match serde_json::from_str::<ErrorResponseContainer>(error_text) {
+        Ok(error_container) => match error_container.error.moderation() {
+            Some(moderation) => return moderation.into(),
+            None => error_container.error.describe(),
+        },
+        Err(_) => error_text.to_string(),
+    };
+    ApiError::from_status(status, message).into()
+}
+
+/// Returns true if the error text from the API indicates that the prompt was too
+/// large for the model's context window.
+fn is_context_length_error(error_text: &str) -> bool {
+    const MARKERS: [&str; 5] = [
+        "context_length_exceeded",
+        "context length",
+        "context window",
+        "maximum context",
+        "prompt is too long",
+    ];
+    let error_text = error_text.to_lowercase();
+    MARKERS.iter().any(|marker| error_text.contains(marker))
+}
+
+/// Roughly estimates the number of tokens in `text`, assuming about four characters
+/// per token. This is only meant for budgeting and will not match any real tokenizer.
+pub fn estimate_tokens(text: &str) -> u64 {
+    (text.chars().count() as u64).div_ceil(4)
+}
+
+/// Parses a simple transcript into a list of chat messages.
+///
+/// Lines beginning with `system:`, `user:` or `assistant:` start a new message with that
+/// role. Any other line is appended to the previous message, or starts a user message if
+/// no role has been seen yet.
+pub fn parse_role_transcript(transcript: &str) -> Vec<ChatMessage> {
+    const ROLES: [&str; 3] = ["system", "user", "assistant"];
+    let mut messages: Vec<ChatMessage> = Vec::new();
+
+    for line in transcript.lines() {
+        let role_prefix = ROLES.iter().find_map(|role| {
+            line.strip_prefix(role)
+                .and_then(|rest| rest.strip_prefix(':'))
+                .map(|rest| (*role, rest.strip_prefix(' ').unwrap_or(rest)))
+        });
+
+        match (role_prefix, messages.last_mut()) {
+            (Some((role, text)), _) => messages.push(ChatMessage {
+                role: role.to_string(),
+                content: text.to_string(),
+            }),
+            (None, Some(previous)) => {
+                // a role line with nothing after it starts its message on the next line
+                if !previous.content.is_empty() {
+                    previous.content.push('\n');
+                }
+                previous.content.push_str(line);
+            }
+            (None, None) => messages.push(ChatMessage {
+                role: "user".to_string(),
+                content: line.to_string(),
+            }),
+        }
+    }
+
+    // blank lines between turns shouldn't end up as trailing whitespace in the messages
+    for message in messages.iter_mut() {
+        message.content.truncate(message.content.trim_end().len());
+    }
+    messages
+}
+
+/// Extracts the code from the first fenced code block in `content`, dropping the fences and
+/// the language tag that follows the opening fence.
+///
+/// A block that's never closed, such as when the response was cut off, runs to the end of
+/// the content.
+///
+/// # Returns
+/// - The lines of code each ended by a newline, or `content` unchanged if it has no fence.
+pub fn strip_code_fences(content: &str) -> String {
+    let mut lines = content.lines();
+    let Some((marker, length)) = lines.by_ref().find_map(|line| {
+        let line = line.trim_start();
+        let marker = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+        let length = line.chars().take_while(|c| *c == marker).count();
+        (length >= 3).then_some((marker, length))
+    }) else {
+        return content.to_string();
+    };
+
+    let mut code = String::new();
+    for line in lines {
+        // the closing fence is made of at least as many of the same characters as the opening
+        let trimmed = line.trim();
+        if trimmed.len() >= length && trimmed.chars().all(|c| c == marker) {
+            break;
+        }
+        code.push_str(line);
+        code.push('\n');
+    }
+    code
+}
+
+/// Counts the characters of text in a chat message, whether its content is a plain
+/// string or an array of content parts.
+fn message_text_length(message: &serde_json::Value) -> usize {
+    match &message["content"] {
+        serde_json::Value::String(text) => text.chars().count(),
+        serde_json::Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .map(|text| text.chars().count())
+            .sum(),
+        _ => 0,
+    }
+}
+
+/// Names a schema after the file it came from, keeping to the letters, digits, `_` and `-`
+/// that OpenAI allows in a schema's name.
+fn schema_name(path: &str) -> String {
+    let stem = std::path::Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let name: String = stem
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    match name.is_empty() {
+        true => "response".to_string(),
+        false => name,
+    }
+}
+
+/// Shortens every string in `value` that's a long run of base64, like an attached image,
+/// to its first few characters and a note of how many were left out.
+fn shorten_base64(value: &mut serde_json::Value) {
+    // how much of the base64 is kept, after any `data:` URL prefix
+    const KEPT_CHARS: usize = 48;
+
+    match value {
+        serde_json::Value::String(text) => {
+            let data = match text.split_once(";base64,") {
+                Some((prefix, data)) if prefix.starts_with("data:") => data,
+                _ => text.as_str(),
+            };
+            let is_base64 = data
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'='));
+            if is_base64 && data.len() > KEPT_CHARS * 2 {
+                let cut = text.len() - data.len() + KEPT_CHARS;
+                let omitted = data.len() - KEPT_CHARS;
+                *text = format!("{}...({} more characters)", &text[..cut], omitted);
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(shorten_base64),
+        serde_json::Value::Object(fields) => fields.values_mut().for_each(shorten_base64),
+        _ => {}
+    }
+}
+
+/// Returns the SHA-256 digest of `data` as lowercase hex.
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Derives a deterministic seed from the prompt by interpreting the first
+/// eight bytes of its SHA-256 digest as a big-endian `i64`.
+fn seed_from_prompt(prompt: &str) -> i64 {
+    let digest = Sha256::digest(prompt.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    i64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    // A chunk of an OpenAI style stream carrying the text "Hi"
+    const HI_CHUNK: &str = r#"{"id":"gen-1","model":"test/model","object":"chat.completion.chunk","created":1,"choices":[{"index":0,"delta":{"content":"Hi"},"finish_reason":null}]}"#;
+
+    // A writer whose output stays readable after the client it's given to takes ownership
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Takes every complete line out of `buffer`.
+    fn drain_lines(buffer: &mut String) -> Vec<String> {
+        std::iter::from_fn(|| take_line(buffer)).collect()
+    }
+
+    /// Builds a client from command line arguments, the same way the binary parses them.
+    fn client(args: &[&str]) -> ApiClient {
+        recording_client(args).0
+    }
+
+    /// Builds a client like `client` along with the text its callback has received.
+    fn recording_client(args: &[&str]) -> (ApiClient, Arc<Mutex<String>>) {
+        let args = std::iter::once("evocaition").chain(args.iter().copied());
+        let output = Arc::new(Mutex::new(String::new()));
+        let received = output.clone();
+        let client = ApiClient::new(Config::parse_from(args), move |text| {
+            received.lock().unwrap().push_str(text);
+            Ok(())
+        });
+        (client, output)
+    }
+
+    #[test]
+    fn leading_blank_lines_are_skipped_and_the_data_parses() {
+        let mut buffer = format!("\n\ndata: {}\n", HI_CHUNK);
+        let lines = drain_lines(&mut buffer);
+        assert!(buffer.is_empty());
+
+        let kinds: Vec<_> = lines
+            .iter()
+            .map(|line| classify_stream_line(line, ApiStyle::OpenAi))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                StreamLine::Blank,
+                StreamLine::Blank,
+                StreamLine::Data(HI_CHUNK)
+            ]
+        );
+        let response: Response = serde_json::from_str(HI_CHUNK).unwrap();
+        assert_eq!(response.choices.len(), 1);
+    }
+
+    #[test]
+    fn a_trailing_partial_line_waits_for_the_rest() {
+        let mut buffer = format!("data: {}\ndata: {{\"id\":", HI_CHUNK);
+        assert_eq!(drain_lines(&mut buffer), [format!("data: {}", HI_CHUNK)]);
+        assert_eq!(buffer, "data: {\"id\":");
+
+        buffer.push_str("\"x\"}\n");
+        assert_eq!(drain_lines(&mut buffer), ["data: {\"id\":\"x\"}"]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn comment_lines_never_reach_the_callback() {
+        let (client, output) = recording_client(&[]);
+        let mut buffer = format!(
+            ": OPENROUTER PROCESSING\n\n: OPENROUTER PROCESSING\ndata: {0}\n\n\
+             : OPENROUTER PROCESSING\ndata: {0}\n\ndata: [DONE]\n",
+            HI_CHUNK
+        );
+        let mut completion = Completion::default();
+
+        assert!(client
+            .process_stream_lines(&mut buffer, &mut completion)
+            .unwrap());
+        assert_eq!(*output.lock().unwrap(), "HiHi");
+        assert_eq!(completion.content, "HiHi");
+    }
+
+    #[test]
+    fn an_error_after_deltas_marks_the_response_as_cut_off() {
+        // color depends on whether stderr is a terminal, which isn't the point here
+        let (client, output) = recording_client(&["--color", "never"]);
+        let diagnostics = SharedBuffer::default();
+        let client = client.with_diagnostics(diagnostics.clone());
+        let mut buffer = format!(
+            "data: {0}\n\ndata: {0}\n\ndata: {{\"error\":{{\"code\":502,\"message\":\"boom\"}}}}\n\n",
+            HI_CHUNK
+        );
+        let mut completion = Completion::default();
+
+        let e = client
+            .process_stream_lines(&mut buffer, &mut completion)
+            .unwrap_err();
+        client.finish_cut_off_response(&completion, &e);
+
+        assert_eq!(*output.lock().unwrap(), "HiHi\n");
+        assert_eq!(
+            diagnostics.text(),
+            "(the response was cut off by an error)\n"
+        );
+        assert!(e.to_string().contains("boom"), "{}", e);
+    }
+
+    #[test]
+    fn the_event_callback_keeps_its_own_state() {
+        let config = Config::parse_from(["evocaition"]);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let received = seen.clone();
+        let mut count = 0;
+        let client = ApiClient::with_event_callback(config, move |event| {
+            count += 1;
+            received.lock().unwrap().push((
+                count,
+                event.delta.to_string(),
+                event.cumulative.to_string(),
+            ));
+        });
+        let mut buffer = format!("data: {0}\n\ndata: {0}\n\n", HI_CHUNK);
+        let mut completion = Completion::default();
+
+        client
+            .process_stream_lines(&mut buffer, &mut completion)
+            .unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                (1, "Hi".to_string(), "Hi".to_string()),
+                (2, "Hi".to_string(), "HiHi".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn an_emoji_split_across_chunks_is_decoded_whole() {
+        let emoji = "\u{1F600}".as_bytes();
+        assert_eq!(emoji.len(), 4);
+
+        let mut undecoded = b"hi ".to_vec();
+        undecoded.extend_from_slice(&emoji[..2]);
+        assert_eq!(decode_complete_utf8(&mut undecoded), "hi ");
+        assert_eq!(undecoded, &emoji[..2]);
+
+        undecoded.extend_from_slice(&emoji[2..]);
+        undecoded.extend_from_slice(b" done");
+        assert_eq!(decode_complete_utf8(&mut undecoded), "\u{1F600} done");
+        assert!(undecoded.is_empty());
+    }
+
+    #[test]
+    fn invalid_bytes_in_the_middle_are_replaced() {
+        let mut undecoded = b"a\xFFb".to_vec();
+        assert_eq!(decode_complete_utf8(&mut undecoded), "a\u{FFFD}b");
+        assert!(undecoded.is_empty());
+
+        // a cut off character after the invalid byte still waits for the rest of it
+        let emoji = "\u{1F600}".as_bytes();
+        let mut undecoded = b"a\xFFb".to_vec();
+        undecoded.extend_from_slice(&emoji[..3]);
+        assert_eq!(decode_complete_utf8(&mut undecoded), "a\u{FFFD}b");
+        assert_eq!(undecoded, &emoji[..3]);
+    }
+
+    #[test]
+    fn stop_values_serialize_as_a_list() {
+        let body = client(&["--stop", "a", "--stop", "b"])
+            .build_request_body("hi")
+            .unwrap();
+        assert_eq!(body["stop"], json!(["a", "b"]));
+
+        let body = client(&["--stop", "a"]).build_request_body("hi").unwrap();
+        assert_eq!(body["stop"], json!(["a"]));
+
+        let body = client(&[]).build_request_body("hi").unwrap();
+        assert!(body.get("stop").is_none());
+    }
+
+    #[test]
+    fn stop_values_map_to_each_api_style() {
+        let body = client(&["--api-style", "anthropic", "--stop", "a", "--stop", "b"])
+            .build_request_body("hi")
+            .unwrap();
+        assert_eq!(body["stop_sequences"], json!(["a", "b"]));
+        assert!(body.get("stop").is_none());
+
+        let body = client(&["--api-style", "ollama", "--stop", "a", "--stop", "b"])
+            .build_request_body("hi")
+            .unwrap();
+        assert_eq!(body["options"]["stop"], json!(["a", "b"]));
+        assert!(body.get("stop").is_none());
+    }
+
+    #[test]
+    fn done_ends_the_stream() {
+        assert_eq!(
+            classify_stream_line("data: [DONE]", ApiStyle::OpenAi),
+            StreamLine::Done
+        );
+        assert_eq!(
+            classify_stream_line("data:[DONE]", ApiStyle::OpenAi),
+            StreamLine::Done
+        );
+        assert_eq!(
+            classify_stream_line("event: message_stop", ApiStyle::Anthropic),
+            StreamLine::Other
+        );
+        assert_eq!(
+            classify_stream_line("{\"done\":true}", ApiStyle::Ollama),
+            StreamLine::Data("{\"done\":true}")
+        );
+    }
+
+    #[test]
+    fn the_first_fenced_block_is_extracted() {
+        let content = "Here you go:\n```rust\nfn main() {}\n```\nAnd another:\n```\nx\n```\n";
+        assert_eq!(strip_code_fences(content), "fn main() {}\n");
+
+        // a longer closing fence of the same character closes it, while a shorter one doesn't
+        let content = "~~~~\n~~~\ncode\n~~~~~\nafter";
+        assert_eq!(strip_code_fences(content), "~~~\ncode\n");
+    }
+
+    #[test]
+    fn a_fence_left_open_runs_to_the_end() {
+        let content = "```python\nprint('cut')\nprint('off')";
+        assert_eq!(strip_code_fences(content), "print('cut')\nprint('off')\n");
+
+        assert_eq!(strip_code_fences("```\n"), "");
+    }
+
+    #[test]
+    fn content_without_a_fence_is_unchanged() {
+        assert_eq!(
+            strip_code_fences("no code here\n``not a fence``"),
+            "no code here\n``not a fence``"
+        );
+        assert_eq!(strip_code_fences(""), "");
+    }
+
+    /// Returns each message as a `(role, content)` pair so they can be compared easily.
+    fn roles_and_contents(messages: &[ChatMessage]) -> Vec<(&str, &str)> {
+        messages
+            .iter()
+            .map(|m| (m.role.as_str(), m.content.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn role_lines_start_messages_and_other_lines_continue_them() {
+        let messages =
+            parse_role_transcript("system: Be brief.\nuser: Hi\nthere\n\nassistant:Hello!\n\n");
+        assert_eq!(
+            roles_and_contents(&messages),
+            [
+                ("system", "Be brief."),
+                ("user", "Hi\nthere"),
+                ("assistant", "Hello!")
+            ]
+        );
+    }
+
+    #[test]
+    fn blank_and_unknown_roles_are_handled() {
+        // text before any role is the user's, and an unknown role is just text
+        let messages = parse_role_transcript(
+            "bot: hello\nuser:\n\nwhat's up?\nassistant: hi\ntool: x\nUser: y",
+        );
+        assert_eq!(
+            roles_and_contents(&messages),
+            [
+                ("user", "bot: hello"),
+                ("user", "what's up?"),
+                ("assistant", "hi\ntool: x\nUser: y")
+            ]
+        );
+
+        assert!(parse_role_transcript("").is_empty());
+    }
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("gpt-4o", "gpt-4o"), 0);
+        // characters rather than bytes are compared
+        assert_eq!(edit_distance("café", "cafe"), 1);
+    }
+
+    #[test]
+    fn edit_distance_to_an_empty_string_is_the_length() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("abc", ""), 3);
+    }
+
+    #[test]
+    fn the_seed_from_a_prompt_is_stable() {
+        // the first eight bytes of the SHA-256 of "hello", 2cf24dba5fb0a30e
+        assert_eq!(seed_from_prompt("hello"), 0x2cf2_4dba_5fb0_a30e);
+        assert_eq!(seed_from_prompt("hello"), seed_from_prompt("hello"));
+        assert_ne!(seed_from_prompt("hello"), seed_from_prompt("hello "));
+    }
+
+    /// Builds headers holding just a `Retry-After` of `value`.
+    fn retry_after_headers(value: &str) -> reqwest::header::HeaderMap {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::RETRY_AFTER,
+            reqwest::header::HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn retry_after_reads_seconds() {
+        assert_eq!(
+            retry_after(&retry_after_headers("5")),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            retry_after(&retry_after_headers(" 1.5 ")),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(retry_after(&retry_after_headers("-1")), None);
+    }
+
+    #[test]
+    fn retry_after_reads_an_http_date() {
+        let later = Utc::now() + chrono::Duration::seconds(60);
+        let header = later.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        let wait = retry_after(&retry_after_headers(&header)).unwrap();
+        assert!(
+            wait > Duration::from_secs(50) && wait <= Duration::from_secs(60),
+            "{:?}",
+            wait
+        );
+
+        // a date that has already passed means there's no need to wait
+        let wait = retry_after(&retry_after_headers("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(wait, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_ignores_a_missing_or_unreadable_header() {
+        assert_eq!(retry_after(&reqwest::header::HeaderMap::new()), None);
+        assert_eq!(retry_after(&retry_after_headers("soon")), None);
+    }
+
+    #[test]
+    fn context_length_errors_are_recognized() {
+        assert!(is_context_length_error(
+            r#"{"error":{"code":"context_length_exceeded"}}"#
+        ));
+        assert!(is_context_length_error(
+            "This model's Maximum Context length is 8192 tokens"
+        ));
+        assert!(is_context_length_error("prompt is too long: 210000 tokens"));
+        assert!(!is_context_length_error("Rate limit exceeded"));
+        assert!(!is_context_length_error(""));
     }
 }
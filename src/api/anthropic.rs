@@ -0,0 +1,404 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{ApiClient, Completion, StreamEvent, Usage};
+
+// The version of the Messages API that requests are written against
+pub(super) const API_VERSION: &str = "2023-06-01";
+
+// The Messages API requires `max_tokens`, so this is used when it isn't configured
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+#[derive(Debug, Deserialize, Clone)]
+struct MessagesResponse {
+    id: String,
+    model: String,
+    content: Vec<ContentBlock>,
+
+    // Ex: 'end_turn' | 'max_tokens' | 'stop_sequence' | 'tool_use'
+    stop_reason: Option<String>,
+
+    usage: MessagesUsage,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Thinking {
+        thinking: String,
+    },
+
+    // Redacted thinking, tool use and anything newer than this client
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+struct MessagesUsage {
+    #[serde(default)]
+    input_tokens: u64,
+
+    #[serde(default)]
+    output_tokens: u64,
+
+    // Prompt tokens written to and read from the prompt cache; not counted in `input_tokens`
+    cache_creation_input_tokens: Option<u64>,
+    cache_read_input_tokens: Option<u64>,
+}
+
+impl MessagesUsage {
+    fn to_usage(&self) -> Usage {
+        let prompt_tokens = self.input_tokens
+            + self.cache_creation_input_tokens.unwrap_or_default()
+            + self.cache_read_input_tokens.unwrap_or_default();
+        Usage {
+            prompt_tokens,
+            completion_tokens: self.output_tokens,
+            total_tokens: prompt_tokens + self.output_tokens,
+            cost: None,
+            cache_read_tokens: self.cache_read_input_tokens,
+            cache_write_tokens: self.cache_creation_input_tokens,
+        }
+    }
+}
+
+// The `data: ` payloads of a streamed response; the `event: ` lines repeat the type
+// so they aren't needed.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum MessagesStreamEvent {
+    MessageStart {
+        message: MessageStart,
+    },
+    ContentBlockDelta {
+        delta: BlockDelta,
+    },
+    MessageDelta {
+        delta: MessageDeltaBody,
+        usage: MessagesUsage,
+    },
+    Error {
+        error: MessagesError,
+    },
+
+    // 'ping', 'content_block_start', 'content_block_stop' and 'message_stop'
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct MessageStart {
+    id: String,
+    model: String,
+    usage: MessagesUsage,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockDelta {
+    TextDelta {
+        text: String,
+    },
+    ThinkingDelta {
+        thinking: String,
+    },
+
+    // Signatures and partial tool input
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct MessageDeltaBody {
+    stop_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct MessagesError {
+    // Ex: 'invalid_request_error' | 'overloaded_error'
+    r#type: String,
+    message: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct MessagesErrorContainer {
+    error: MessagesError,
+}
+
+impl ApiClient {
+    /// Constructs the request body for Anthropic's Messages API.
+    ///
+    /// System messages are moved to the top-level `system` field as the API requires, and any
+    /// attached images are sent as `image` content blocks in their own user message ahead of the
+    /// final message. If `cache_context` is set, the context file's block, or else the last
+    /// system block, is marked with `cache_control` so the prefix up to it gets cached. If
+    /// `reasoning_max_tokens` is set, extended thinking is enabled with that budget. Sampling
+    /// parameters the API doesn't support are left out with a warning.
+    ///
+    /// # Parameters
+    /// - `prompt` - A string slice representing the user's input prompt to be sent to the model.
+    ///
+    /// # Returns
+    /// A `serde_json::Value` representing the JSON request body to be sent in the API call, or an
+    /// `Err` if an attached image couldn't be read.
+    pub(super) fn build_anthropic_request_body(&self, prompt: &str) -> Result<serde_json::Value> {
+        let mut system = Vec::new();
+        let mut messages = Vec::new();
+        for message in self.prompt_messages(prompt)? {
+            if message.role == "system" {
+                system.push(message.content);
+            } else {
+                messages.push(json!({
+                    "role": message.role,
+                    "content": message.content,
+                }));
+            }
+        }
+
+        let images = self.image_contents()?;
+        if !images.is_empty() {
+            let content: Vec<serde_json::Value> = images
+                .iter()
+                .map(|image_content| {
+                    let source = match image_content
+                        .strip_prefix("data:")
+                        .and_then(|rest| rest.split_once(";base64,"))
+                    {
+                        Some((media_type, data)) => json!({
+                            "type": "base64",
+                            "media_type": media_type,
+                            "data": data,
+                        }),
+                        None => json!({
+                            "type": "url",
+                            "url": image_content,
+                        }),
+                    };
+                    json!({
+                        "type": "image",
+                        "source": source,
+                    })
+                })
+                .collect();
+            let image_message = json!({
+                "role": "user",
+                "content": content,
+            });
+            messages.insert(messages.len().saturating_sub(1), image_message);
+        }
+
+        // the thinking budget counts against max_tokens, so leave room for the answer too
+        let max_tokens = self
+            .config
+            .max_tokens
+            .unwrap_or(DEFAULT_MAX_TOKENS + self.config.reasoning_max_tokens.unwrap_or_default());
+        let mut body = json!({
+            "model": self.config.model_id,
+            "max_tokens": max_tokens,
+            "messages": messages,
+            "stream": self.config.stream,
+        });
+        if self.config.cache_context {
+            // the context file is always the first system message
+            let cached = if self.config.context_file.is_some() {
+                0
+            } else {
+                system.len().saturating_sub(1)
+            };
+            let blocks: Vec<serde_json::Value> = system
+                .iter()
+                .enumerate()
+                .map(|(i, text)| {
+                    let mut block = json!({"type": "text", "text": text});
+                    if i == cached {
+                        block["cache_control"] = json!({"type": "ephemeral"});
+                    }
+                    block
+                })
+                .collect();
+            if blocks.is_empty() {
+                self.warn("--cache-context needs a system prompt or --context-file to cache");
+            } else {
+                body["system"] = json!(blocks);
+            }
+        } else if !system.is_empty() {
+            body["system"] = json!(system.join("\n\n"));
+        }
+
+        // add in the optional parameters that the Messages API supports
+        if let Some(temp) = self.config.temp {
+            body["temperature"] = json!(temp);
+        }
+        if let Some(top_k) = self.config.top_k {
+            body["top_k"] = json!(top_k);
+        }
+        if !self.config.stop.is_empty() {
+            body["stop_sequences"] = json!(self.config.stop);
+        }
+        if let Some(top_p) = self.config.top_p {
+            body["top_p"] = json!(top_p);
+        }
+        if let Some(budget_tokens) = self.config.reasoning_max_tokens {
+            body["thinking"] = json!({
+                "type": "enabled",
+                "budget_tokens": budget_tokens,
+            });
+        }
+
+        let unsupported = [
+            ("--min-p", self.config.min_p.is_some()),
+            ("--rep-pen", self.config.rep_pen.is_some()),
+            ("--presence-penalty", self.config.presence_penalty.is_some()),
+            (
+                "--frequency-penalty",
+                self.config.frequency_penalty.is_some(),
+            ),
+            (
+                "--seed",
+                self.config.seed.is_some() || self.config.seed_from_prompt,
+            ),
+            ("--json", self.config.json),
+            ("--provider-order", !self.config.provider_order.is_empty()),
+            ("--no-fallbacks", self.config.no_fallbacks),
+            ("--json-schema", self.config.json_schema.is_some()),
+            ("--choices", self.config.choices.is_some()),
+            ("--tools-file", self.config.tools_file.is_some()),
+            ("--tool-choice", self.config.tool_choice.is_some()),
+        ];
+        for (flag, _) in unsupported.iter().filter(|(_, is_set)| *is_set) {
+            self.warn(&format!(
+                "{} is not supported by the anthropic API style and will be ignored",
+                flag
+            ));
+        }
+
+        Ok(body)
+    }
+
+    /// Processes a non-streaming response from the Messages API.
+    ///
+    /// The text blocks of the response are joined together and sent to the callback, while any
+    /// thinking blocks are treated as reasoning.
+    ///
+    /// # Parameters
+    /// - `response_text`: A string slice containing the JSON response text from the API.
+    ///
+    /// # Returns
+    /// - The `Completion` indicating success or an Err indicating failure.
+    pub(super) fn process_anthropic_response(&self, response_text: &str) -> Result<Completion> {
+        match serde_json::from_str::<MessagesResponse>(response_text) {
+            Ok(message) => {
+                let mut completion = Completion {
+                    id: Some(message.id),
+                    model: Some(message.model),
+                    finish_reason: message.stop_reason,
+                    usage: Some(message.usage.to_usage()),
+                    ..Default::default()
+                };
+                for block in message.content {
+                    match block {
+                        ContentBlock::Text { text } => completion.content.push_str(&text),
+                        ContentBlock::Thinking { thinking } => {
+                            self.emit_reasoning(&thinking);
+                            self.emit_reasoning("\n");
+                        }
+                        ContentBlock::Other => {}
+                    }
+                }
+
+                self.emit(StreamEvent {
+                    delta: &completion.content,
+                    cumulative: &completion.content,
+                    model: completion.model.as_deref(),
+                    finish_reason: completion.finish_reason.as_deref(),
+                    usage: completion.usage.as_ref(),
+                })?;
+                Ok(completion)
+            }
+            Err(parse_error) => match serde_json::from_str::<MessagesErrorContainer>(response_text)
+            {
+                Ok(error_container) => Err(anyhow!(
+                    "API request failed with {}: {}",
+                    error_container.error.r#type,
+                    error_container.error.message
+                )),
+                Err(_) => Err(anyhow!(
+                    "Failed to parse JSON: {}\nRaw JSON: {}",
+                    parse_error,
+                    response_text
+                )),
+            },
+        }
+    }
+
+    /// Processes the JSON payload of a single `data: ` line from a Messages API stream.
+    ///
+    /// Text deltas are appended to `completion` and reported to the callback and thinking deltas
+    /// are treated as reasoning. The input token count arrives at the start of the stream and the
+    /// output token count and stop reason arrive at the end.
+    ///
+    /// # Returns
+    /// - `Ok(())` if the payload was handled, or an `Err` if it was an error event or couldn't be parsed.
+    pub(super) fn process_anthropic_stream_data(
+        &self,
+        json_str: &str,
+        completion: &mut Completion,
+    ) -> Result<()> {
+        let event = serde_json::from_str::<MessagesStreamEvent>(json_str)
+            .map_err(|e| anyhow!("Failed to parse JSON: {}\nRaw JSON: {}", e, json_str))?;
+
+        match event {
+            MessagesStreamEvent::MessageStart { message } => {
+                completion.id = Some(message.id);
+                completion.model = Some(message.model);
+                completion.usage = Some(message.usage.to_usage());
+            }
+            MessagesStreamEvent::ContentBlockDelta { delta } => match delta {
+                BlockDelta::TextDelta { text } => {
+                    completion.content.push_str(&text);
+                    self.emit(StreamEvent {
+                        delta: &text,
+                        cumulative: &completion.content,
+                        model: completion.model.as_deref(),
+                        finish_reason: None,
+                        usage: None,
+                    })?;
+                }
+                BlockDelta::ThinkingDelta { thinking } => self.emit_reasoning(&thinking),
+                BlockDelta::Other => {}
+            },
+            MessagesStreamEvent::MessageDelta { delta, usage } => {
+                // the prompt and cache counts only arrive with the start of the message
+                let start_usage = completion.usage.take().unwrap_or_default();
+                let usage = Usage {
+                    completion_tokens: usage.output_tokens,
+                    total_tokens: start_usage.prompt_tokens + usage.output_tokens,
+                    ..start_usage
+                };
+                self.emit(StreamEvent {
+                    delta: "",
+                    cumulative: &completion.content,
+                    model: completion.model.as_deref(),
+                    finish_reason: delta.stop_reason.as_deref(),
+                    usage: Some(&usage),
+                })?;
+                completion.finish_reason = delta.stop_reason;
+                completion.usage = Some(usage);
+            }
+            MessagesStreamEvent::Error { error } => {
+                return Err(anyhow!(
+                    "API request failed with {}: {}",
+                    error.r#type,
+                    error.message
+                ));
+            }
+            MessagesStreamEvent::Other => {}
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+use anyhow::{anyhow, Result};
+use chrono::DateTime;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{ApiClient, Completion, StreamEvent, Usage};
+use crate::config;
+
+#[derive(Debug, Deserialize, Clone)]
+struct ChatResponse {
+    model: String,
+
+    // RFC 3339 timestamp, Ex: '2023-08-04T19:22:45.499127Z'
+    created_at: Option<String>,
+
+    message: Option<ChatResponseMessage>,
+
+    // Only true for the final object of a response
+    #[serde(default)]
+    done: bool,
+
+    // Ex: 'stop' | 'length' | 'load'
+    done_reason: Option<String>,
+
+    // The token counts are only sent with the final object
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+
+    // Reported in place of a response if the request failed partway
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct ChatResponseMessage {
+    #[serde(default)]
+    content: String,
+
+    // Only sent by thinking models when `think` is enabled
+    thinking: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl ChatResponse {
+    fn created(&self) -> Option<u64> {
+        self.created_at
+            .as_deref()
+            .and_then(|created_at| DateTime::parse_from_rfc3339(created_at).ok())
+            .and_then(|created_at| u64::try_from(created_at.timestamp()).ok())
+    }
+
+    fn usage(&self) -> Option<Usage> {
+        if !self.done {
+            return None;
+        }
+        let prompt_tokens = self.prompt_eval_count.unwrap_or_default();
+        let completion_tokens = self.eval_count.unwrap_or_default();
+        Some(Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            cost: None,
+            cache_read_tokens: None,
+            cache_write_tokens: None,
+        })
+    }
+}
+
+impl ApiClient {
+    /// Constructs the request body for Ollama's native `/api/chat` endpoint.
+    ///
+    /// The sampling parameters are sent in the `options` object using Ollama's names for them,
+    /// and any attached images are sent as base64 in the `images` field of their own user message
+    /// ahead of the final message. Ollama can't download images, so image URLs are rejected.
+    /// If `reasoning_max_tokens` is set, thinking is enabled but the budget itself is ignored.
+    ///
+    /// # Parameters
+    /// - `prompt` - A string slice representing the user's input prompt to be sent to the model.
+    ///
+    /// # Returns
+    /// A `serde_json::Value` representing the JSON request body to be sent in the API call, or an
+    /// `Err` if an attached image couldn't be read.
+    pub(super) fn build_ollama_request_body(&self, prompt: &str) -> Result<serde_json::Value> {
+        let mut messages: Vec<serde_json::Value> = self
+            .prompt_messages(prompt)?
+            .iter()
+            .map(|message| json!(message))
+            .collect();
+
+        let images = self.image_contents()?;
+        if !images.is_empty() {
+            let data = images
+                .iter()
+                .map(|image_content| {
+                    image_content
+                        .strip_prefix("data:")
+                        .and_then(|rest| rest.split_once(";base64,"))
+                        .map(|(_, data)| data)
+                        .ok_or_else(|| {
+                            anyhow!("The ollama API style only supports images from local files")
+                        })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let image_message = json!({
+                "role": "user",
+                "content": "",
+                "images": data,
+            });
+            messages.insert(messages.len().saturating_sub(1), image_message);
+        }
+
+        let mut options = serde_json::Map::new();
+        if let Some(max_tokens) = self.config.max_tokens {
+            options.insert("num_predict".to_string(), json!(max_tokens));
+        }
+        if let Some(temp) = self.config.temp {
+            options.insert("temperature".to_string(), json!(temp));
+        }
+        if let Some(top_k) = self.config.top_k {
+            options.insert("top_k".to_string(), json!(top_k));
+        }
+        if let Some(top_p) = self.config.top_p {
+            options.insert("top_p".to_string(), json!(top_p));
+        }
+        if let Some(min_p) = self.config.min_p {
+            options.insert("min_p".to_string(), json!(min_p));
+        }
+        if let Some(rep_pen) = self.config.rep_pen {
+            options.insert("repeat_penalty".to_string(), json!(rep_pen));
+        }
+        if let Some(presence_penalty) = self.config.presence_penalty {
+            options.insert("presence_penalty".to_string(), json!(presence_penalty));
+        }
+        if let Some(frequency_penalty) = self.config.frequency_penalty {
+            options.insert("frequency_penalty".to_string(), json!(frequency_penalty));
+        }
+        if !self.config.stop.is_empty() {
+            options.insert("stop".to_string(), json!(self.config.stop));
+        }
+        if let Some(seed) = self.resolve_seed(prompt) {
+            options.insert("seed".to_string(), json!(seed));
+        }
+
+        let mut body = json!({
+            "model": self.config.model_id,
+            "messages": messages,
+            "stream": self.config.stream,
+        });
+        if !options.is_empty() {
+            body["options"] = serde_json::Value::Object(options);
+        }
+        // Ollama takes either "json" or the schema itself as the format
+        if self.config.json {
+            body["format"] = json!("json");
+        }
+        if let Some(path) = &self.config.json_schema {
+            let schema = config::read_json_schema(path).map_err(|e| anyhow!(e))?;
+            body["format"] = schema.get("schema").cloned().unwrap_or(schema);
+        }
+        let unsupported = [
+            ("--choices", self.config.choices.is_some()),
+            ("--tools-file", self.config.tools_file.is_some()),
+            ("--tool-choice", self.config.tool_choice.is_some()),
+        ];
+        for (flag, _) in unsupported.iter().filter(|(_, is_set)| *is_set) {
+            self.warn(&format!(
+                "{} is not supported by the ollama API style and will be ignored",
+                flag
+            ));
+        }
+        if self.config.reasoning_max_tokens.is_some() {
+            self.warn("the ollama API style doesn't support a thinking budget; thinking is enabled without one");
+            body["think"] = json!(true);
+        }
+
+        Ok(body)
+    }
+
+    /// Processes a non-streaming response from Ollama's `/api/chat` endpoint.
+    ///
+    /// # Parameters
+    /// - `response_text`: A string slice containing the JSON response text from the API.
+    ///
+    /// # Returns
+    /// - The `Completion` indicating success or an Err indicating failure.
+    pub(super) fn process_ollama_response(&self, response_text: &str) -> Result<Completion> {
+        let mut completion = Completion::default();
+        self.process_ollama_stream_data(response_text, &mut completion)?;
+        Ok(completion)
+    }
+
+    /// Processes a single line of the newline-delimited JSON that Ollama streams.
+    ///
+    /// Each line carries the next piece of the message, which is appended to `completion` and
+    /// reported to the callback, while thinking is treated as reasoning. The final line has
+    /// `done` set along with the stop reason and token counts.
+    ///
+    /// # Returns
+    /// - `Ok(())` if the line was handled, or an `Err` if it was an error or couldn't be parsed.
+    pub(super) fn process_ollama_stream_data(
+        &self,
+        json_str: &str,
+        completion: &mut Completion,
+    ) -> Result<()> {
+        let response = match serde_json::from_str::<ChatResponse>(json_str) {
+            Ok(response) => response,
+            Err(parse_error) => {
+                return match serde_json::from_str::<ErrorResponse>(json_str) {
+                    Ok(error_response) => {
+                        Err(anyhow!("API request failed: {}", error_response.error))
+                    }
+                    Err(_) => Err(anyhow!(
+                        "Failed to parse JSON: {}\nRaw JSON: {}",
+                        parse_error,
+                        json_str
+                    )),
+                };
+            }
+        };
+        if let Some(error) = &response.error {
+            return Err(anyhow!("API request failed: {}", error));
+        }
+
+        completion.model = Some(response.model.clone());
+        completion.created = response.created().or(completion.created);
+        let usage = response.usage();
+        let delta = match &response.message {
+            Some(message) => {
+                if let Some(thinking) = &message.thinking {
+                    self.emit_reasoning(thinking);
+                }
+                message.content.as_str()
+            }
+            None => "",
+        };
+        completion.content.push_str(delta);
+        self.emit(StreamEvent {
+            delta,
+            cumulative: &completion.content,
+            model: completion.model.as_deref(),
+            finish_reason: response.done_reason.as_deref(),
+            usage: usage.as_ref(),
+        })?;
+        if response.done_reason.is_some() {
+            completion.finish_reason = response.done_reason;
+        }
+        if usage.is_some() {
+            completion.usage = usage;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+use anyhow::{anyhow, Result};
+use std::{io, path::PathBuf, sync::Arc};
+
+use evocaition::{
+    api::{ApiClient, ChatMessage, SessionUsage},
+    config::Config,
+    error, style,
+};
+
+use crate::markdown;
+
+// The prompt shown when waiting for the user's next message
+const PROMPT: &str = "> ";
+
+/// Runs an interactive conversation, sending each line the user enters along with the
+/// conversation so far and writing out the replies as they arrive.
+///
+/// Pressing Ctrl-C while a reply is being received aborts that reply and leaves it out of
+/// the conversation. Entering `/reset` starts the conversation over, and it ends with
+/// `/exit` or at the end of input, such as Ctrl-D. With `history_file`, the conversation
+/// picks up from the messages saved there and is saved back after every change, and the
+/// file is remembered as the last session so that `resume` can pick it up again without
+/// being given its path. With `export_markdown`, the conversation is exported as Markdown
+/// after every reply.
+///
+/// Each reply is written out through its own copy of `callback`.
+pub async fn run<F>(mut config: Config, callback: F) -> Result<()>
+where
+    F: FnMut(&str) -> io::Result<()> + Clone + Send + 'static,
+{
+    if config.resume {
+        config.history_file = Some(last_session()?);
+    }
+    if let Some(path) = &config.history_file {
+        record_last_session(path, config.use_color());
+    }
+    let mut reader = LineReader::new(config.use_color())?;
+    let mut history = match &config.history_file {
+        Some(path) => load_history(path)?,
+        None => Vec::new(),
+    };
+    if !history.is_empty() {
+        style::report(
+            &format!("(resuming a conversation of {} messages)", history.len()),
+            config.use_color(),
+        );
+    }
+    let session = Arc::new(SessionUsage::default());
+    // the turns share a connection to the API rather than each opening their own
+    let shared = ApiClient::new(config.clone(), |_| Ok(()));
+    let started = chrono::Local::now();
+
+    while let Some(line) = reader.read_line(PROMPT)? {
+        match line.trim() {
+            "" => continue,
+            "/exit" => break,
+            "/reset" => {
+                history.clear();
+                save_history(&config, &history);
+                style::report("(conversation reset)", config.use_color());
+                continue;
+            }
+            _ => {}
+        }
+
+        let mut run_config = config.clone();
+        run_config.prompt = Some(line.clone());
+        let api_client = ApiClient::new(run_config, callback.clone())
+            .with_shared_state(&shared)
+            .with_history(history.clone())
+            .with_session(session.clone());
+        tokio::select! {
+            result = api_client.do_completion() => match result {
+                Ok(completion) => {
+                    history.push(ChatMessage {
+                        role: "user".to_string(),
+                        content: line,
+                    });
+                    history.push(ChatMessage {
+                        role: "assistant".to_string(),
+                        content: completion.content,
+                    });
+                    save_history(&config, &history);
+                    if let Some(path) = &config.export_markdown {
+                        if let Err(e) = markdown::export(path, &config.model_id, &started, &history)
+                        {
+                            style::warn(&e.to_string(), config.use_color());
+                        }
+                    }
+                }
+                Err(e) if error::is_broken_pipe(&e) => return Err(e),
+                Err(e) => style::error(&e.to_string(), config.use_color()),
+            },
+            _ = tokio::signal::ctrl_c() => {
+                style::report("\n(reply aborted)", config.use_color())
+            }
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+/// Returns where the path of the last session's `history_file` is kept, or `None` if the
+/// platform has no config directory for it.
+fn last_session_path() -> Option<PathBuf> {
+    evocaition::config::project_dirs().map(|dirs| dirs.config_dir().join("last_session"))
+}
+
+/// Reads the path of the `history_file` most recently used by a chat.
+///
+/// # Returns
+/// - The path, or an `Err` if no chat has saved its history yet.
+fn last_session() -> Result<String> {
+    let no_session =
+        || anyhow!("There's no conversation to resume; save one with '--history-file' first");
+    let path = last_session_path().ok_or_else(no_session)?;
+    match std::fs::read_to_string(&path) {
+        Ok(text) if !text.trim().is_empty() => Ok(text.trim_end_matches(['\n', '\r']).to_string()),
+        Ok(_) => Err(no_session()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(no_session()),
+        Err(e) => Err(anyhow!(
+            "Unable to read the last session from '{}': {}",
+            path.display(),
+            e
+        )),
+    }
+}
+
+/// Remembers `history_file` as the last session for `resume`, as an absolute path so that
+/// it's found again from any directory. A failure is only warned about so the chat can
+/// carry on.
+fn record_last_session(history_file: &str, color: bool) {
+    let Some(path) = last_session_path() else {
+        return;
+    };
+    let history_file = std::env::current_dir()
+        .map(|dir| dir.join(history_file))
+        .unwrap_or_else(|_| PathBuf::from(history_file));
+    let recorded = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| std::fs::write(&path, format!("{}\n", history_file.display())));
+    if let Err(e) = recorded {
+        style::warn(
+            &format!(
+                "unable to record the last session in '{}': {}",
+                path.display(),
+                e
+            ),
+            color,
+        );
+    }
+}
+
+/// Reads the messages of a conversation saved by `save_history`.
+///
+/// # Returns
+/// - The saved messages, or none if the file doesn't exist or is empty.
+fn load_history(path: &str) -> Result<Vec<ChatMessage>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow!("Unable to read the history file '{}': {}", path, e)),
+    };
+    if text.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    serde_json::from_str(&text)
+        .map_err(|e| anyhow!("Unable to parse the history file '{}': {}", path, e))
+}
+
+/// Saves the conversation to the configured `history_file`, if any, as a JSON array of
+/// `role`/`content` messages. A failure is only warned about so the chat can carry on.
+fn save_history(config: &Config, history: &[ChatMessage]) {
+    let Some(path) = &config.history_file else {
+        return;
+    };
+    let saved = serde_json::to_string_pretty(history)
+        .map_err(io::Error::from)
+        .and_then(|json| std::fs::write(path, json + "\n"));
+    if let Err(e) = saved {
+        style::warn(
+            &format!("unable to save the history file '{}': {}", path, e),
+            config.use_color(),
+        );
+    }
+}
+
+/// Reads the user's messages with line editing and a history of previous messages that's
+/// kept between sessions.
+#[cfg(feature = "interactive")]
+struct LineReader {
+    editor: rustyline::DefaultEditor,
+
+    // Where the history is saved; `None` if the platform has no data directory for it
+    history_path: Option<std::path::PathBuf>,
+
+    // Whether the warning about failing to save the history is colored
+    color: bool,
+}
+
+#[cfg(feature = "interactive")]
+impl LineReader {
+    fn new(color: bool) -> Result<Self> {
+        let mut editor = rustyline::DefaultEditor::new()?;
+        let history_path =
+            evocaition::config::project_dirs().map(|dirs| dirs.data_dir().join("chat_history"));
+        if let Some(path) = &history_path {
+            // there's no history yet the first time
+            let _ = editor.load_history(path);
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+        }
+        Ok(LineReader {
+            editor,
+            history_path,
+            color,
+        })
+    }
+
+    /// Returns the next line entered, or `None` once the input has ended.
+    fn read_line(&mut self, prompt: &str) -> Result<Option<String>> {
+        use rustyline::error::ReadlineError;
+
+        loop {
+            match self.editor.readline(prompt) {
+                Ok(line) => {
+                    if !line.trim().is_empty() {
+                        self.editor.add_history_entry(line.as_str())?;
+                        if let Some(path) = &self.history_path {
+                            if let Err(e) = self.editor.save_history(path) {
+                                style::warn(
+                                    &format!("unable to save the chat history: {}", e),
+                                    self.color,
+                                );
+                            }
+                        }
+                    }
+                    return Ok(Some(line));
+                }
+                // Ctrl-C only clears the line being edited
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Reads the user's messages from stdin one line at a time.
+#[cfg(not(feature = "interactive"))]
+struct LineReader;
+
+#[cfg(not(feature = "interactive"))]
+impl LineReader {
+    fn new(_color: bool) -> Result<Self> {
+        Ok(LineReader)
+    }
+
+    /// Returns the next line entered, or `None` once the input has ended.
+    fn read_line(&mut self, prompt: &str) -> Result<Option<String>> {
+        use std::io::Write;
+
+        print!("{}", prompt);
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim_end_matches(['\n', '\r']).to_string()))
+    }
+}
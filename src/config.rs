@@ -1,12 +1,53 @@
-use clap::Parser;
-use std::env;
+use chrono::format::{Item, StrftimeItems};
+use clap::{
+    error::ErrorKind, parser::ValueSource, ArgGroup, ArgMatches, CommandFactory, FromArgMatches,
+    Parser, ValueEnum,
+};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize, Serializer};
+use std::{
+    env, fmt,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-#[derive(Debug, Parser)]
+use crate::encoding;
+
+// The options that the `model_defaults` of the config file can set for a model
+const MODEL_DEFAULT_OPTIONS: [&str; 11] = [
+    "max_tokens",
+    "temp",
+    "top_p",
+    "min_p",
+    "top_k",
+    "rep_pen",
+    "presence_penalty",
+    "frequency_penalty",
+    "stop",
+    "seed",
+    "reasoning_max_tokens",
+];
+
+// The options that `--client` uses itself rather than leaving to the daemon
+#[cfg(all(unix, feature = "daemon"))]
+const CLIENT_OPTIONS: [&str; 6] = [
+    "client",
+    "prompt",
+    "prompt_file",
+    "input_encoding",
+    "color",
+    "config_file",
+];
+
+#[derive(Debug, Clone, Parser, Serialize, Deserialize)]
 #[clap(
     name = "evocaition",
     version = "0.1.0",
     author = "Timothy Bogdala",
-    about = "A command-line tool to interact with AI LLMs via APIs. Reads from STDIN if '--prompt' is not supplied."
+    about = "A command-line tool to interact with AI LLMs via APIs. Reads from STDIN if '--prompt' is not supplied.",
+    // either way of sending a batch of prompts, for the options that apply to both
+    group(ArgGroup::new("batch").args(["batch_jsonl_input", "batch_file"]))
 )]
 pub struct Config {
     #[clap(
@@ -17,14 +58,127 @@ pub struct Config {
     )]
     pub api: String,
 
+    #[clap(
+        long,
+        value_name = "STYLE",
+        help = "The style of API the endpoint speaks",
+        value_enum,
+        default_value_t = ApiStyle::OpenAi
+    )]
+    pub api_style: ApiStyle,
+
     #[clap(
         long("key"),
         value_name = "API_KEY",
-        help = "Sets the API key for remote endpoint; if absent, the envvar 'OPENROUTER_API_KEY' is checked",
+        help = "Sets the API key for remote endpoint; if absent, '--key-file' and then the envvar 'OPENROUTER_API_KEY' are checked",
         default_value = ""
     )]
+    #[serde(serialize_with = "redact")]
     pub api_key: String,
 
+    #[clap(
+        long("config"),
+        value_name = "FILE",
+        help = "Read default options from this TOML or JSON file instead of 'config.toml' or 'config.json' in the platform's config directory"
+    )]
+    pub config_file: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "NAME",
+        help = "Apply the named preset of options from the config file's 'presets' table or the 'presets' directory beside it; flags still take precedence"
+    )]
+    pub preset: Option<String>,
+
+    #[clap(
+        long("key-file"),
+        visible_alias = "api-key-file",
+        value_name = "FILE",
+        help = "Read the API key from FILE, such as a mounted secret; '--key' takes precedence"
+    )]
+    pub api_key_file: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Print the resolved configuration as JSON with the API key redacted and exit",
+        default_value_t = false
+    )]
+    pub echo_config: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Print the request body to stdout and its URL and headers to stderr, then exit without sending it",
+        default_value_t = false,
+        conflicts_with_all = ["chat", "follow", "batch_jsonl_input", "batch_file", "temp_ramp", "deterministic_check"]
+    )]
+    pub dry_run: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Print the sampling parameters supported by '--model-id' and exit",
+        default_value_t = false
+    )]
+    pub list_parameters: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Print the models available from the API, with their context length and pricing if known, and exit; with '--json', print the list as the API returned it",
+        default_value_t = false
+    )]
+    pub list_models: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Only use HTTP/1.1, for proxies that break HTTP/2 or streamed responses",
+        default_value_t = false
+    )]
+    pub http1_only: bool,
+
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        help = "Give up if connecting to the API, or waiting for it to send anything more, takes longer than SECONDS; long streams aren't cut off while text keeps arriving",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub timeout: Option<u64>,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Do not send the 'HTTP-Referer' and 'X-Title' attribution headers",
+        default_value_t = false
+    )]
+    pub no_attribution: bool,
+
+    #[clap(
+        long,
+        value_name = "URL",
+        help = "The URL of your app to send in the 'HTTP-Referer' attribution header, instead of evocaition's",
+        conflicts_with = "no_attribution"
+    )]
+    pub referer: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "STRING",
+        help = "The name of your app to send in the 'X-Title' attribution header, instead of 'evocaition'",
+        conflicts_with = "no_attribution"
+    )]
+    pub app_title: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "NAME: VALUE",
+        help = "An extra HTTP header to send with every request, replacing any built-in one of the same name; can be given more than once",
+        value_parser = parse_header
+    )]
+    pub header: Vec<String>,
+
     #[clap(
         long,
         value_name = "PROMPT",
@@ -32,6 +186,14 @@ pub struct Config {
     )]
     pub prompt: Option<String>,
 
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Read the prompt for the AI from FILE instead of STDIN",
+        conflicts_with_all = ["prompt", "chat", "follow", "batch_jsonl_input", "batch_file"]
+    )]
+    pub prompt_file: Option<String>,
+
     #[clap(
         short('n'),
         long,
@@ -40,6 +202,23 @@ pub struct Config {
     )]
     pub max_tokens: Option<u32>,
 
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Set the maximum tokens to whatever is left of the model's context after the prompt",
+        default_value_t = false,
+        conflicts_with = "max_tokens"
+    )]
+    pub max_tokens_auto: bool,
+
+    #[clap(
+        long,
+        value_name = "INT",
+        help = "The model's context window in tokens, for local checks like '--max-tokens-auto' when the models list doesn't report it; the server isn't told",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub context_length: Option<u64>,
+
     #[clap(
         long,
         value_name = "MODEL_ID",
@@ -48,6 +227,61 @@ pub struct Config {
     )]
     pub model_id: String,
 
+    #[clap(
+        long,
+        visible_alias = "verify-model-exists",
+        value_name = "BOOL",
+        help = "Check that the model is in OpenRouter's models list before sending, suggesting close matches if it isn't",
+        default_value_t = false
+    )]
+    pub verify_model: bool,
+
+    #[clap(
+        long,
+        value_name = "MODEL_ID",
+        help = "A larger context model to retry with if the prompt exceeds the context length of '--model-id'"
+    )]
+    pub fallback_model: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "NAME",
+        help = "Ask OpenRouter to try this upstream provider for the model, in the order given; can be given more than once"
+    )]
+    pub provider_order: Vec<String>,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Don't let OpenRouter fall back to providers other than those of '--provider-order'",
+        default_value_t = false
+    )]
+    pub no_fallbacks: bool,
+
+    #[clap(
+        long,
+        visible_alias = "retries",
+        value_name = "INT",
+        help = "How many times to retry a request that failed with a transient error",
+        default_value_t = 0
+    )]
+    pub max_retries: u32,
+
+    #[clap(
+        long,
+        value_name = "INT",
+        help = "The delay in milliseconds before the first retry; it doubles for each retry after",
+        default_value_t = 500
+    )]
+    pub retry_base_ms: u64,
+
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        help = "The most time to spend on a request and its retries before giving up with the last error"
+    )]
+    pub retry_budget: Option<f64>,
+
     #[clap(
         short('s'),
         long,
@@ -57,6 +291,194 @@ pub struct Config {
     )]
     pub stream: bool,
 
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Write the raw SSE stream to stdout byte-for-byte without parsing it; requires '--stream'",
+        default_value_t = false,
+        requires = "stream"
+    )]
+    pub passthrough: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Write the response to stdout as the API sent it without parsing it; when streaming, the data of each event is written on its own line",
+        default_value_t = false,
+        conflicts_with_all = ["passthrough", "output_template", "json_stream_aggregate", "as_sse", "chat", "follow", "batch_jsonl_input", "batch_file", "temp_ramp", "deterministic_check"]
+    )]
+    pub raw: bool,
+
+    #[clap(
+        long,
+        visible_alias = "stream-chunk-size",
+        value_name = "INT",
+        help = "Gather at least this many characters, or up to a newline, before writing out streamed text",
+        requires = "stream"
+    )]
+    pub chunk_size: Option<usize>,
+
+    #[clap(
+        long,
+        value_name = "MILLIS",
+        help = "Wait at least this many milliseconds between writes of streamed text, gathering it in between",
+        requires = "stream",
+        conflicts_with = "passthrough"
+    )]
+    pub min_interval: Option<u64>,
+
+    #[clap(
+        long,
+        visible_alias = "prefix-timestamps",
+        value_name = "BOOL",
+        help = "Write streamed text a line at a time, prefixing each line with an ISO-8601 timestamp",
+        default_value_t = false,
+        requires = "stream",
+        conflicts_with_all = ["chunk_size", "passthrough"]
+    )]
+    pub timestamps: bool,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "If a streamed response fails partway, save the text received so far and the error to FILE"
+    )]
+    pub save_on_error: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Append the prompt, model and response of every completion to FILE as JSONL"
+    )]
+    pub transcript_log: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Write the text of the response to FILE as it arrives instead of to stdout"
+    )]
+    pub output: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Write the response to stdout as well as the '--output' file",
+        default_value_t = false,
+        requires = "output"
+    )]
+    pub tee: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Add the response to the end of the '--output' file instead of replacing its contents",
+        default_value_t = false,
+        requires = "output",
+        conflicts_with = "split_output"
+    )]
+    pub append: bool,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Also save the response and its metadata, like the model and usage, to FILE as JSON"
+    )]
+    pub output_json: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "BYTES",
+        help = "Write the '--output' file as it streams in, starting a new numbered part every BYTES bytes and listing them in a manifest",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "output",
+        conflicts_with_all = ["output_template", "json_stream_aggregate"]
+    )]
+    pub split_output: Option<u64>,
+
+    #[clap(
+        long,
+        value_name = "COMMAND",
+        help = "Run COMMAND through the shell after the response is finished, with its text on stdin and EVOCAITION_MODEL and EVOCAITION_TOKENS set"
+    )]
+    pub on_finish: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Run the '--on-finish' command even when the request fails, with EVOCAITION_STATUS set to 'error'",
+        default_value_t = false,
+        requires = "on_finish"
+    )]
+    pub on_finish_always: bool,
+
+    #[clap(
+        long,
+        value_name = "INT",
+        help = "Print summaries of the last INT requests in the '--transcript-log' and exit",
+        requires = "transcript_log"
+    )]
+    pub list_recent: Option<usize>,
+
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        help = "Write the response using a template with placeholders like {content}, {model}, {timestamp} and {usage.total}",
+        conflicts_with = "stream"
+    )]
+    pub output_template: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Stream the response from the API but write it out as a single JSON object once it's finished",
+        default_value_t = false,
+        conflicts_with_all = ["output_template", "passthrough", "chat", "follow", "batch_jsonl_input", "batch_file", "temp_ramp"]
+    )]
+    pub json_stream_aggregate: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Write the response to stdout as an OpenAI style server-sent event stream ending with '[DONE]', for relaying to clients that expect one",
+        default_value_t = false,
+        conflicts_with_all = ["output_template", "json_stream_aggregate", "passthrough", "chat", "follow", "batch_jsonl_input", "batch_file", "temp_ramp"]
+    )]
+    pub as_sse: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Write a '[model-id]' header line naming the model that generated the response ahead of it",
+        default_value_t = false,
+        conflicts_with_all = ["output_template", "json_stream_aggregate", "passthrough"]
+    )]
+    pub echo_model_in_output: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Write only the code inside the first ``` fenced block of the response, if it has one",
+        default_value_t = false,
+        conflicts_with = "stream"
+    )]
+    pub strip_code_fences: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Remove any newlines from the end of the response and never write one after it",
+        default_value_t = false
+    )]
+    pub no_trailing_newline: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Remove whitespace from the start and end of the response",
+        default_value_t = false
+    )]
+    pub trim: bool,
+
     #[clap(
         long,
         value_name = "BOOL",
@@ -65,69 +487,1174 @@ pub struct Config {
     )]
     pub plain: bool,
 
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Parse the prompt as a transcript of 'system:', 'user:' and 'assistant:' prefixed lines",
+        default_value_t = false,
+        conflicts_with = "plain"
+    )]
+    pub parse_roles: bool,
+
     #[clap(long, value_name = "F32", help = "Sets the temperature for sampling")]
     pub temp: Option<f32>,
 
     #[clap(
         long,
-        value_name = "F32",
-        help = "Include only the top tokens whose probabilities add up to P when sampling"
+        value_name = "START:END:STEPS",
+        help = "Run the prompt STEPS times, varying the temperature linearly from START to END",
+        value_parser = parse_temp_ramp,
+        conflicts_with = "temp"
     )]
-    pub top_p: Option<f32>,
+    pub temp_ramp: Option<TempRamp>,
 
     #[clap(
         long,
-        value_name = "F32",
-        help = "The minimum probability for a token relative to the most probable token when sampling"
+        value_name = "FORMAT",
+        help = "How to label the outputs of '--temp-ramp'",
+        value_enum,
+        default_value_t = RampFormat::Blocks
     )]
-    pub min_p: Option<f32>,
+    pub temp_ramp_format: RampFormat,
 
     #[clap(
         long,
-        value_name = "INT",
-        help = "Include only this amount of top tokens when sampling"
+        value_name = "BOOL",
+        help = "Have an interactive conversation, sending each line entered along with the conversation so far",
+        default_value_t = false,
+        conflicts_with_all = ["prompt", "plain"]
     )]
-    pub top_k: Option<u32>,
+    pub chat: bool,
 
     #[clap(
         long,
-        value_name = "F32",
-        help = "A higher value makes the model less likely to repeat tokens"
+        value_name = "FILE",
+        help = "Save the conversation of '--chat' to FILE as JSON after every reply, resuming it from there if it exists",
+        requires = "chat"
     )]
-    pub rep_pen: Option<f32>,
+    pub history_file: Option<String>,
 
     #[clap(
         long,
-        value_name = "INT",
-        help = "The seed to use for the generation (determinism is not guaranteed)"
+        value_name = "BOOL",
+        help = "Continue the conversation most recently saved by '--history-file' without naming its file",
+        default_value_t = false,
+        requires = "chat",
+        conflicts_with = "history_file"
     )]
-    pub seed: Option<i64>,
+    pub resume: bool,
 
+    #[cfg(all(unix, feature = "daemon"))]
     #[clap(
-        long("image"),
-        value_name = "FILEPATH or URL",
-        help = "An image to attach to the user's request; '--plain' must not be used."
+        long,
+        value_name = "SOCKET",
+        help = "Keep running and serve the prompts sent by '--client' over the Unix socket SOCKET",
+        conflicts_with_all = ["prompt", "chat", "follow", "batch_jsonl_input", "batch_file", "client"]
     )]
-    pub image_file: Option<String>,
-}
+    pub daemon: Option<String>,
 
-impl Config {
-    pub fn from_cli() -> Self {
-        let mut config = Config::parse();
+    #[cfg(all(unix, feature = "daemon"))]
+    #[clap(
+        long,
+        value_name = "SOCKET",
+        help = "Send the prompt to the '--daemon' listening on the Unix socket SOCKET and write out its response; the daemon's own options are used, so only the prompt options can be given with it",
+        conflicts_with_all = ["chat", "follow", "batch_jsonl_input", "batch_file"]
+    )]
+    pub client: Option<String>,
 
-        // Fallback to environment variable if api_key is not provided
-        if config.api_key.is_empty() {
-            match env::var("OPENROUTER_API_KEY") {
-                Ok(key) => {
-                    config.api_key = key;
-                    config
-                }
-                Err(_) => panic!(
-                    "API key must be provided via --key or OPENROUTER_API_KEY environment variable"
-                ),
-            }
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Watch FILE like 'tail -f' and send each newly appended line or block as a prompt",
+        conflicts_with = "prompt"
+    )]
+    pub follow: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "MODE",
+        help = "Whether '--follow' sends each line, or each block of lines ended by a blank line",
+        value_enum,
+        default_value_t = FollowMode::Line
+    )]
+    pub follow_mode: FollowMode,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Keep the conversation going across the prompts read by '--follow'",
+        default_value_t = false,
+        requires = "follow",
+        conflicts_with = "plain"
+    )]
+    pub follow_history: bool,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Keep FILE updated with the conversation of '--chat' or '--follow' as Markdown, for sharing"
+    )]
+    pub export_markdown: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Send a prompt for every JSON object in FILE, writing each object back out with a 'completion' field",
+        requires = "prompt_template",
+        conflicts_with_all = ["prompt", "follow"]
+    )]
+    pub batch_jsonl_input: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        help = "The prompt to send for each object of '--batch-jsonl-input', with {field} placeholders for its fields",
+        requires = "batch_jsonl_input"
+    )]
+    pub prompt_template: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Send every line of FILE, or every block of lines ended by a blank line with '--batch-mode block', as its own prompt",
+        conflicts_with_all = ["prompt", "follow", "batch_jsonl_input"]
+    )]
+    pub batch_file: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "MODE",
+        help = "Whether '--batch-file' sends each line, or each block of lines ended by a blank line",
+        value_enum,
+        default_value_t = FollowMode::Line,
+        requires = "batch_file"
+    )]
+    pub batch_mode: FollowMode,
+
+    #[clap(
+        long,
+        value_name = "STRING",
+        help = "The line written between the responses of '--batch-file'",
+        default_value = "---",
+        requires = "batch_file"
+    )]
+    pub batch_delimiter: String,
+
+    #[clap(
+        long,
+        value_name = "INT",
+        help = "How many batch requests may be in flight at once",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub concurrency: u32,
+
+    #[clap(
+        long,
+        value_name = "INT",
+        help = "The most idle connections to the API kept open for reuse; unlimited by default, and best kept at least as high as '--concurrency'"
+    )]
+    pub max_connections: Option<usize>,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Once a batch is finished, summarize its failures on stderr and exit with an error if there were any",
+        default_value_t = false,
+        requires = "batch"
+    )]
+    pub summarize_errors: bool,
+
+    #[clap(
+        long,
+        value_name = "F32",
+        help = "Include only the top tokens whose probabilities add up to P when sampling"
+    )]
+    pub top_p: Option<f32>,
+
+    #[clap(
+        long,
+        value_name = "F32",
+        help = "The minimum probability for a token relative to the most probable token when sampling"
+    )]
+    pub min_p: Option<f32>,
+
+    #[clap(
+        long,
+        value_name = "INT",
+        help = "Include only this amount of top tokens when sampling"
+    )]
+    pub top_k: Option<u32>,
+
+    #[clap(
+        long,
+        value_name = "F32",
+        help = "A higher value makes the model less likely to repeat tokens"
+    )]
+    pub rep_pen: Option<f32>,
+
+    #[clap(
+        long,
+        value_name = "F32",
+        help = "From -2.0 to 2.0; positive values penalize tokens that have already appeared at all, encouraging new topics",
+        value_parser = parse_penalty,
+        allow_negative_numbers = true
+    )]
+    pub presence_penalty: Option<f32>,
+
+    #[clap(
+        long,
+        value_name = "F32",
+        help = "From -2.0 to 2.0; positive values penalize tokens by how often they've already appeared",
+        value_parser = parse_penalty,
+        allow_negative_numbers = true
+    )]
+    pub frequency_penalty: Option<f32>,
+
+    #[clap(
+        long,
+        value_name = "STRING",
+        help = "Stop generating when the model writes STRING; can be given more than once"
+    )]
+    pub stop: Vec<String>,
+
+    #[clap(
+        long,
+        alias = "n",
+        value_name = "INT",
+        help = "Ask for INT completions of the prompt, each written under a '--- choice N ---' header; not to be confused with '-n', which is '--max-tokens'",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["chat", "follow", "json_stream_aggregate", "as_sse"]
+    )]
+    pub choices: Option<u32>,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Ask the model to reply with a JSON object",
+        default_value_t = false,
+        conflicts_with = "plain"
+    )]
+    pub json: bool,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Ask the model to reply with JSON matching the JSON schema in FILE",
+        conflicts_with_all = ["json", "plain"]
+    )]
+    pub json_schema: Option<String>,
+
+    #[clap(
+        long,
+        visible_alias = "tools",
+        value_name = "FILE",
+        help = "Offer the model the tools in FILE, a JSON array of OpenAI style tool definitions, to call",
+        conflicts_with = "plain"
+    )]
+    pub tools_file: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "auto|none|required|NAME",
+        help = "Whether the model may, mustn't or must call one of the tools, or the name of the one it must call",
+        requires = "tools_file"
+    )]
+    pub tool_choice: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "INT",
+        help = "The seed to use for the generation (determinism is not guaranteed)"
+    )]
+    pub seed: Option<i64>,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Derive the seed from a SHA-256 hash of the prompt so each prompt is reproducible",
+        default_value_t = false,
+        conflicts_with = "seed"
+    )]
+    pub seed_from_prompt: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Send the same request twice and report whether the outputs are identical; fails if they differ despite a seed",
+        default_value_t = false,
+        conflicts_with_all = ["stream", "output_template", "json_stream_aggregate", "temp_ramp", "chat", "follow", "batch_jsonl_input", "batch_file"]
+    )]
+    pub deterministic_check: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Print the SHA-256 hash of the prompt to stderr to correlate outputs with their inputs",
+        default_value_t = false
+    )]
+    pub show_prompt_hash: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Print the SHA-256 hash of the full request body to stderr",
+        default_value_t = false
+    )]
+    pub show_request_hash: bool,
+
+    #[clap(
+        long,
+        value_name = "INT",
+        help = "Warn if the request would send more than this many messages"
+    )]
+    pub max_messages: Option<usize>,
+
+    #[clap(
+        long,
+        value_name = "INT",
+        help = "Warn if the text of any single message is longer than this many characters"
+    )]
+    pub max_message_chars: Option<usize>,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Turn request validation and unsupported parameter warnings into errors",
+        default_value_t = false
+    )]
+    pub strict: bool,
+
+    #[clap(
+        long,
+        value_name = "INT",
+        help = "The maximum number of tokens a reasoning model may spend thinking before it responds"
+    )]
+    pub reasoning_max_tokens: Option<u32>,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Write the model's reasoning to stderr as it's received; it's hidden by default",
+        default_value_t = false
+    )]
+    pub show_reasoning: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Print the token usage of each request, and of the session so far, to stderr",
+        default_value_t = false
+    )]
+    pub show_usage: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Print why the generation stopped to stderr, such as 'length' when it was cut off by '--max-tokens'",
+        default_value_t = false
+    )]
+    pub show_finish_reason: bool,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "With '--show-usage', also print the completion to prompt token ratio and flag unusually large prompts",
+        default_value_t = false,
+        requires = "show_usage"
+    )]
+    pub compare_usage: bool,
+
+    #[clap(
+        long,
+        value_name = "INT",
+        help = "Refuse to send any more requests once the tokens used by the session would exceed INT"
+    )]
+    pub max_total_tokens: Option<u64>,
+
+    #[clap(
+        long,
+        visible_alias = "stats",
+        value_name = "BOOL",
+        help = "Print the time to first token, tokens per second and inter-token latency statistics of a streamed response to stderr",
+        default_value_t = false,
+        requires = "stream"
+    )]
+    pub token_stats: bool,
+
+    #[clap(
+        long,
+        visible_alias = "filter-empty-deltas",
+        value_name = "BOOL",
+        help = "Print how many deltas of a streamed response had no content to stderr, for spotting providers that pad streams",
+        default_value_t = false,
+        requires = "stream"
+    )]
+    pub show_empty_deltas: bool,
+
+    #[clap(
+        short('q'),
+        long,
+        value_name = "BOOL",
+        help = "Don't write informational messages like retry notices and '--token-stats' to stderr; warnings and errors are still shown",
+        default_value_t = false
+    )]
+    pub quiet: bool,
+
+    #[clap(
+        short('v'),
+        long,
+        help = "Write extra diagnostics to stderr, like the rate limit budget and each request's URL and status; give twice to also log the request bodies and raw response lines",
+        action = clap::ArgAction::Count,
+        conflicts_with = "quiet"
+    )]
+    pub verbose: u8,
+
+    #[clap(
+        long,
+        value_name = "WHEN",
+        help = "Whether to color the usage, warnings and other messages written to stderr; 'auto' colors them when stderr is a terminal and NO_COLOR isn't set",
+        value_enum,
+        default_value_t = ColorChoice::Auto
+    )]
+    pub color: ColorChoice,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Print the cost of the request reported by the provider to stderr, or an estimate from the model's listed pricing if it isn't reported",
+        default_value_t = false
+    )]
+    pub show_cost: bool,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Send the contents of FILE as a system message ahead of the prompt",
+        conflicts_with = "plain"
+    )]
+    pub context_file: Option<String>,
+
+    #[clap(
+        long,
+        visible_alias = "prompt-encoding",
+        value_name = "ENCODING",
+        help = "The encoding of prompt input read from files and stdin, such as 'latin1' or 'shift_jis', which is converted to UTF-8",
+        default_value = "utf-8",
+        value_parser = parse_input_encoding
+    )]
+    pub input_encoding: String,
+
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Mark the context file, or else the system prompt, for Anthropic prompt caching",
+        default_value_t = false
+    )]
+    pub cache_context: bool,
+
+    #[clap(
+        long,
+        visible_alias = "append-system-datetime",
+        value_name = "BOOL",
+        help = "Tell the model the current date and time in a system message, written with '--datetime-format'",
+        default_value_t = false,
+        conflicts_with = "plain"
+    )]
+    pub inject_datetime: bool,
+
+    #[clap(
+        long,
+        value_name = "FORMAT",
+        help = "The strftime format of '--inject-datetime', such as 'Current time: %Y-%m-%d %H:%M %Z'",
+        default_value = "Current date: %Y-%m-%d",
+        value_parser = parse_datetime_format
+    )]
+    pub datetime_format: String,
+
+    #[cfg(feature = "detect-language")]
+    #[clap(
+        long,
+        value_name = "BOOL",
+        help = "Detect the language of the prompt and ask the model to respond in it with a system message written with '--language-hint'",
+        default_value_t = false,
+        conflicts_with = "plain"
+    )]
+    pub detect_language: bool,
+
+    #[cfg(feature = "detect-language")]
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        help = "The system message added by '--detect-language', where {lang} is the language's English name and {code} its ISO 639-3 code",
+        default_value = "Respond in {lang}."
+    )]
+    pub language_hint: String,
+
+    #[clap(
+        long("image"),
+        value_name = "FILEPATH or URL",
+        help = "An image to attach to the user's request; can be given more than once. '--plain' must not be used."
+    )]
+    pub image_file: Vec<String>,
+
+    #[clap(
+        long,
+        value_name = "INT",
+        help = "The largest local image file, in bytes, that will be attached to a request",
+        default_value_t = 20 * 1024 * 1024
+    )]
+    pub max_image_bytes: u64,
+
+    #[clap(
+        long,
+        value_name = "DETAIL",
+        help = "The resolution vision models should use for attached images; omit for the provider default",
+        value_enum
+    )]
+    pub image_detail: Option<ImageDetail>,
+}
+
+/// The styles of API that the endpoint can speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiStyle {
+    // OpenAI compatible completion endpoints, like OpenRouter and LM Studio
+    #[value(name = "openai")]
+    OpenAi,
+
+    // Anthropic's Messages API
+    Anthropic,
+
+    // Ollama's native chat API, which streams newline-delimited JSON
+    Ollama,
+}
+
+/// How much detail a vision model should use when looking at an attached image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageDetail {
+    Low,
+    High,
+    Auto,
+}
+
+impl ImageDetail {
+    /// Returns the value to send in the `detail` field of an image content block.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageDetail::Low => "low",
+            ImageDetail::High => "high",
+            ImageDetail::Auto => "auto",
+        }
+    }
+}
+
+/// A linear range of temperatures to run the same prompt with.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TempRamp {
+    pub start: f32,
+    pub end: f32,
+    pub steps: u32,
+}
+
+impl TempRamp {
+    /// Returns the temperature for the zero-based `step` of the ramp.
+    pub fn temp_at(&self, step: u32) -> f32 {
+        if self.steps <= 1 {
+            self.start
         } else {
-            config
+            self.start + (self.end - self.start) * step as f32 / (self.steps - 1) as f32
         }
     }
 }
+
+/// Parses a `START:END:STEPS` temperature ramp from the command line.
+fn parse_temp_ramp(arg: &str) -> Result<TempRamp, String> {
+    let parts: Vec<&str> = arg.split(':').collect();
+    if parts.len() != 3 {
+        return Err("expected the format START:END:STEPS".to_string());
+    }
+    let start = parts[0]
+        .parse::<f32>()
+        .map_err(|e| format!("invalid START temperature: {}", e))?;
+    let end = parts[1]
+        .parse::<f32>()
+        .map_err(|e| format!("invalid END temperature: {}", e))?;
+    let steps = parts[2]
+        .parse::<u32>()
+        .map_err(|e| format!("invalid STEPS count: {}", e))?;
+    if steps == 0 {
+        return Err("STEPS must be at least 1".to_string());
+    }
+    Ok(TempRamp { start, end, steps })
+}
+
+/// Parses a `--presence-penalty` or `--frequency-penalty`, which providers accept from
+/// -2.0 to 2.0.
+fn parse_penalty(arg: &str) -> Result<f32, String> {
+    let penalty = arg
+        .parse::<f32>()
+        .map_err(|e| format!("invalid penalty: {}", e))?;
+    if !(-2.0..=2.0).contains(&penalty) {
+        return Err("the penalty must be between -2.0 and 2.0".to_string());
+    }
+    Ok(penalty)
+}
+
+/// Checks that a `--header` has the form `Name: Value`, with a name made of the characters
+/// HTTP allows in one and a value without line breaks, and tidies up the spacing.
+fn parse_header(arg: &str) -> Result<String, String> {
+    let Some((name, value)) = arg.split_once(':') else {
+        return Err("the header must have the form 'Name: Value'".to_string());
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("the header's name is empty".to_string());
+    }
+    let is_token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if !name.chars().all(is_token) {
+        return Err(format!("'{}' isn't a valid header name", name));
+    }
+    let value = value.trim();
+    if value.chars().any(|c| c.is_control() && c != '\t') {
+        return Err(
+            "the header's value can't contain line breaks or other control characters".to_string(),
+        );
+    }
+    Ok(format!("{}: {}", name, value))
+}
+
+/// Reads the JSON schema in the file given with `--json-schema`.
+///
+/// # Returns
+/// - The schema, or a description of why the file couldn't be read or isn't a JSON object.
+pub fn read_json_schema(path: &str) -> Result<serde_json::Value, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("unable to read the JSON schema file '{}': {}", path, e))?;
+    match serde_json::from_str(&contents) {
+        Ok(schema @ serde_json::Value::Object(_)) => Ok(schema),
+        Ok(_) => Err(format!(
+            "the JSON schema file '{}' must hold a JSON object",
+            path
+        )),
+        Err(e) => Err(format!(
+            "unable to parse the JSON schema file '{}': {}",
+            path, e
+        )),
+    }
+}
+
+/// Reads the tool definitions in the file given with `--tools-file`.
+///
+/// # Returns
+/// - The tools, or a description of why the file couldn't be read or isn't a JSON array.
+pub fn read_tools_file(path: &str) -> Result<serde_json::Value, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("unable to read the tools file '{}': {}", path, e))?;
+    match serde_json::from_str(&contents) {
+        Ok(tools @ serde_json::Value::Array(_)) => Ok(tools),
+        Ok(_) => Err(format!(
+            "the tools file '{}' must hold a JSON array of tool definitions",
+            path
+        )),
+        Err(e) => Err(format!("unable to parse the tools file '{}': {}", path, e)),
+    }
+}
+
+/// Checks that a strftime format for `--datetime-format` only uses known specifiers.
+fn parse_datetime_format(arg: &str) -> Result<String, String> {
+    if StrftimeItems::new(arg).any(|item| matches!(item, Item::Error)) {
+        return Err("unknown or incomplete % specifier".to_string());
+    }
+    Ok(arg.to_string())
+}
+
+/// Checks that `--input-encoding` names an encoding that input can be decoded from.
+fn parse_input_encoding(arg: &str) -> Result<String, String> {
+    encoding::lookup(arg).map_err(|e| e.to_string())?;
+    Ok(arg.to_string())
+}
+
+/// The ways the outputs of a temperature ramp can be written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RampFormat {
+    // Each output is preceded by a header line naming its temperature
+    Blocks,
+
+    // Each output is written as a JSON object on its own line
+    Jsonl,
+}
+
+/// When the messages written to stderr are colored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    // Only when stderr is a terminal and the NO_COLOR environment variable isn't set
+    Auto,
+
+    // Even when stderr is redirected
+    Always,
+
+    // Not at all
+    Never,
+}
+
+/// How the text of a followed file or a batch file is split into prompts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FollowMode {
+    // Every non-empty line is a prompt
+    Line,
+
+    // Lines are gathered into a prompt until a blank line
+    Block,
+}
+
+impl Config {
+    /// Returns whether the messages written to stderr should be colored. An explicit
+    /// `--color always` wins over `NO_COLOR`, which only turns off the automatic coloring.
+    pub fn use_color(&self) -> bool {
+        match self.color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                let no_color = matches!(env::var_os("NO_COLOR"), Some(v) if !v.is_empty());
+                !no_color && io::stderr().is_terminal()
+            }
+        }
+    }
+
+    pub fn from_cli() -> Self {
+        let matches = Config::command().get_matches();
+        let mut config = Config::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        // the daemon sends every prompt with its own options, so a client can't take others
+        #[cfg(all(unix, feature = "daemon"))]
+        if config.client.is_some() {
+            check_client_options(&matches);
+        }
+
+        // options not given on the command line can come from the config file
+        let file_api_key = config.apply_config_file(&matches);
+
+        // a prompt file is read up front so that it's used everywhere `--prompt` would be
+        if let Some(path) = &config.prompt_file {
+            match encoding::read_file(path, &config.input_encoding, "prompt file") {
+                Ok(prompt) => config.prompt = Some(prompt),
+                Err(e) => Config::command().error(ErrorKind::Io, e).exit(),
+            }
+        }
+
+        // a broken schema or tools file is caught before anything is sent
+        if let Some(path) = &config.json_schema {
+            if let Err(e) = read_json_schema(path) {
+                Config::command().error(ErrorKind::InvalidValue, e).exit();
+            }
+        }
+        if let Some(path) = &config.tools_file {
+            if let Err(e) = read_tools_file(path) {
+                Config::command().error(ErrorKind::InvalidValue, e).exit();
+            }
+        }
+
+        // then the key file, which keeps the key out of process listings and shell history
+        if let (true, Some(path)) = (config.api_key.is_empty(), &config.api_key_file) {
+            match std::fs::read_to_string(path) {
+                Ok(key) if !key.trim().is_empty() => config.api_key = key.trim().to_string(),
+                Ok(_) => Config::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!("the API key file '{}' is empty", path),
+                    )
+                    .exit(),
+                Err(e) => Config::command()
+                    .error(
+                        ErrorKind::Io,
+                        format!("unable to read the API key file '{}': {}", path, e),
+                    )
+                    .exit(),
+            }
+        }
+
+        // Fallback to environment variable if api_key is not provided, and then the config file
+        if config.api_key.is_empty() {
+            match env::var("OPENROUTER_API_KEY").ok().or(file_api_key) {
+                Some(key) => config.api_key = key,
+                // none of these talk to the API, and echoing the configuration is how
+                // a user would find out the key is missing
+                None if config.echo_config || config.dry_run || config.list_recent.is_some() => {}
+                // the daemon has the key
+                #[cfg(all(unix, feature = "daemon"))]
+                None if config.client.is_some() => {}
+                None => panic!(
+                    "API key must be provided via --key, --key-file, OPENROUTER_API_KEY environment variable or the config file"
+                ),
+            }
+        }
+
+        // sampling parameters that weren't given as flags fall back to the environment,
+        // ahead of the config file, except where they'd conflict with a flag that was given
+        if config.temp_ramp.is_none() {
+            env_default(&mut config.temp, "EVOCAITION_TEMP", given("temp"));
+        }
+        env_default(&mut config.top_p, "EVOCAITION_TOP_P", given("top_p"));
+        env_default(&mut config.min_p, "EVOCAITION_MIN_P", given("min_p"));
+        env_default(&mut config.top_k, "EVOCAITION_TOP_K", given("top_k"));
+        env_default(&mut config.rep_pen, "EVOCAITION_REP_PEN", given("rep_pen"));
+        env_default_with(
+            &mut config.presence_penalty,
+            "EVOCAITION_PRESENCE_PENALTY",
+            given("presence_penalty"),
+            parse_penalty,
+        );
+        env_default_with(
+            &mut config.frequency_penalty,
+            "EVOCAITION_FREQUENCY_PENALTY",
+            given("frequency_penalty"),
+            parse_penalty,
+        );
+        // several stop sequences are separated by commas, since there's only one variable
+        let mut stop = None;
+        env_default_with(&mut stop, "EVOCAITION_STOP", given("stop"), |raw| {
+            Ok::<_, String>(
+                raw.split(',')
+                    .filter(|sequence| !sequence.is_empty())
+                    .map(str::to_string)
+                    .collect::<Vec<_>>(),
+            )
+        });
+        if let Some(stop) = stop {
+            config.stop = stop;
+        }
+        if !config.seed_from_prompt {
+            env_default(&mut config.seed, "EVOCAITION_SEED", given("seed"));
+        }
+        config
+    }
+
+    /// Sets the options in the config file that weren't given on the command line.
+    ///
+    /// The file is a TOML table or JSON object with any of the options named the way
+    /// `--echo-config` writes them. It's read from `--config` if that was given, or otherwise
+    /// from `config.toml` or, failing that, `config.json` in the platform's config directory
+    /// if either exists. Files ending in `.toml` are read as TOML and any others as JSON.
+    /// Problems with the file exit with a usage error.
+    ///
+    /// The selected `preset` is applied over the file's other options. It's looked up in the
+    /// file's `presets` table first, and then as `<NAME>.toml` or `<NAME>.json` in a `presets`
+    /// directory beside the config file, or in the platform's config directory if there's
+    /// no config file.
+    ///
+    /// The file may also have a `model_defaults` table of sampling parameters keyed by
+    /// model ID or by a prefix of one, like `anthropic/`. The entry with the longest key
+    /// that the selected model's ID starts with is applied over the file's other options,
+    /// so an exact model ID always beats a prefix, though not over the preset's.
+    ///
+    /// # Returns
+    /// - The API key from the file, which is only used once the other ways of giving the
+    ///   key have been checked.
+    fn apply_config_file(&mut self, matches: &ArgMatches) -> Option<String> {
+        let path = match &self.config_file {
+            Some(path) => Some(PathBuf::from(path)),
+            None => project_dirs().and_then(|dirs| {
+                ["config.toml", "config.json"]
+                    .iter()
+                    .map(|name| dirs.config_dir().join(name))
+                    .find(|path| path.exists())
+            }),
+        };
+        let mut options = match &path {
+            Some(path) => read_options(path),
+            None if self.preset.is_some() => serde_json::Map::new(),
+            None => return None,
+        };
+        let model_defaults = options.remove("model_defaults");
+        let presets = options.remove("presets");
+
+        // a preset given on the command line replaces one the file picks
+        let preset_name = match (&self.preset, options.get("preset")) {
+            (Some(name), _) => Some(name.clone()),
+            (None, Some(name)) => Some(name.as_str().map(str::to_string).unwrap_or_else(|| {
+                config_error(path.as_deref(), "'preset' must be the name of a preset")
+            })),
+            (None, None) => None,
+        };
+        let preset = preset_name.map(|name| find_preset(&name, path.as_deref(), presets));
+
+        // the serialized key is redacted, so it's kept aside while the options are merged
+        let api_key = std::mem::take(&mut self.api_key);
+        let mut merged = serde_json::to_value(&*self)
+            .unwrap_or_else(|e| config_error(path.as_deref(), e.to_string()));
+        let unmerged = merged.clone();
+        let mut file_api_key = None;
+        for (name, value) in options {
+            if merged.get(&name).is_none() {
+                config_error(path.as_deref(), format!("unknown option '{}'", name));
+            }
+            if matches.value_source(&name) == Some(ValueSource::CommandLine) {
+                continue;
+            }
+            match name.as_str() {
+                "api_key" => file_api_key = value.as_str().map(str::to_string),
+                _ => merged[&name] = value,
+            }
+        }
+
+        // then the preset, remembering what it set so the model defaults leave it alone
+        let mut preset_options = Vec::new();
+        if let Some((source, options)) = preset {
+            let source = Some(source.as_path());
+            for (name, value) in options {
+                if merged.get(&name).is_none() {
+                    config_error(source, format!("unknown option '{}'", name));
+                }
+                if ["api_key", "config_file", "preset"].contains(&name.as_str()) {
+                    config_error(source, format!("'{}' can't be set by a preset", name));
+                }
+                if matches.value_source(&name) != Some(ValueSource::CommandLine) {
+                    merged[&name] = value;
+                    preset_options.push(name);
+                }
+            }
+        }
+
+        // then the defaults for whichever model ended up selected
+        if let Some(model_defaults) = model_defaults {
+            let model_defaults = model_defaults.as_object().unwrap_or_else(|| {
+                config_error(
+                    path.as_deref(),
+                    "'model_defaults' must be an object keyed by model ID",
+                )
+            });
+            let model_id = merged["model_id"].as_str().unwrap_or_default();
+            let matched = model_defaults
+                .iter()
+                .filter(|(key, _)| model_id.starts_with(key.as_str()))
+                .max_by_key(|(key, _)| key.len());
+            if let Some((key, defaults)) = matched {
+                let defaults = defaults.as_object().unwrap_or_else(|| {
+                    config_error(
+                        path.as_deref(),
+                        format!("the model defaults for '{}' must be an object", key),
+                    )
+                });
+                for (name, value) in defaults {
+                    if !MODEL_DEFAULT_OPTIONS.contains(&name.as_str()) {
+                        config_error(
+                            path.as_deref(),
+                            format!(
+                                "'{}' can't be set in the model defaults for '{}'; only sampling parameters can",
+                                name, key
+                            ),
+                        );
+                    }
+                    if matches.value_source(name) != Some(ValueSource::CommandLine)
+                        && !preset_options.contains(name)
+                    {
+                        merged[name] = value.clone();
+                    }
+                }
+            }
+        }
+        check_merged_conflicts(&unmerged, &merged, matches, path.as_deref());
+        *self = serde_json::from_value(merged)
+            .unwrap_or_else(|e| config_error(path.as_deref(), e.to_string()));
+        self.api_key = api_key;
+        file_api_key.filter(|key| !key.is_empty())
+    }
+}
+
+/// Checks the options merged in from the config file, its preset and model defaults
+/// against the conflicts that clap only checks among the flags on the command line, such
+/// as `stream` with `strip_code_fences`, exiting with a usage error naming both options if
+/// one that was merged in conflicts with another that's set.
+///
+/// # Parameters
+/// - `unmerged`: The options as they were parsed from the command line.
+/// - `merged`: The options after everything was merged in.
+fn check_merged_conflicts(
+    unmerged: &serde_json::Value,
+    merged: &serde_json::Value,
+    matches: &ArgMatches,
+    path: Option<&Path>,
+) {
+    let merged_in = |id: &str| unmerged.get(id) != merged.get(id);
+    let set =
+        |id: &str| merged_in(id) || matches.value_source(id) == Some(ValueSource::CommandLine);
+    let command = Config::command();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if !set(id) {
+            continue;
+        }
+        for other in command.get_arg_conflicts_with(arg) {
+            let other_id = other.get_id().as_str();
+            if set(other_id) && (merged_in(id) || merged_in(other_id)) {
+                config_error(path, format!("'{}' can't be used with '{}'", id, other_id));
+            }
+        }
+    }
+}
+
+/// Exits with a usage error if an option that `--client` would ignore was given along with
+/// it, since the daemon sends every prompt with the options it was started with.
+#[cfg(all(unix, feature = "daemon"))]
+fn check_client_options(matches: &ArgMatches) {
+    let command = Config::command();
+    let ignored = command.get_arguments().find(|arg| {
+        let id = arg.get_id().as_str();
+        matches.value_source(id) == Some(ValueSource::CommandLine) && !CLIENT_OPTIONS.contains(&id)
+    });
+    if let Some(arg) = ignored {
+        let name = arg.get_long().unwrap_or(arg.get_id().as_str());
+        command
+            .clone()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "'--{}' can't be used with '--client'; the daemon sends every prompt with the options it was started with",
+                    name
+                ),
+            )
+            .exit();
+    }
+}
+
+/// Exits with a usage error about the config file at `path`, or about the options in
+/// general if there's no file.
+fn config_error(path: Option<&Path>, message: impl fmt::Display) -> ! {
+    let message = match path {
+        Some(path) => format!("config file '{}': {}", path.display(), message),
+        None => message.to_string(),
+    };
+    Config::command()
+        .error(ErrorKind::InvalidValue, message)
+        .exit()
+}
+
+/// Reads the options in a config or preset file, as TOML if its name ends in `.toml` and
+/// as JSON otherwise, exiting with a usage error if it can't be read.
+fn read_options(path: &Path) -> serde_json::Map<String, serde_json::Value> {
+    let contents =
+        std::fs::read_to_string(path).unwrap_or_else(|e| config_error(Some(path), e.to_string()));
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => {
+            toml::from_str(&contents).unwrap_or_else(|e| config_error(Some(path), e.to_string()))
+        }
+        _ => serde_json::from_str(&contents)
+            .unwrap_or_else(|e| config_error(Some(path), e.to_string())),
+    }
+}
+
+/// Finds the options of the preset called `name`, in the `presets` table of the config
+/// file at `config_path` or else in a file of the `presets` directory, exiting with a usage
+/// error if there's no such preset.
+///
+/// # Returns
+/// - The file the preset came from, for reporting problems with it, and its options.
+fn find_preset(
+    name: &str,
+    config_path: Option<&Path>,
+    presets: Option<serde_json::Value>,
+) -> (PathBuf, serde_json::Map<String, serde_json::Value>) {
+    if let (Some(path), Some(presets)) = (config_path, presets) {
+        let presets = match presets {
+            serde_json::Value::Object(presets) => presets,
+            _ => config_error(
+                Some(path),
+                "'presets' must be an object keyed by preset name",
+            ),
+        };
+        if let Some(preset) = presets.get(name) {
+            match preset {
+                serde_json::Value::Object(options) => return (path.to_path_buf(), options.clone()),
+                _ => config_error(
+                    Some(path),
+                    format!("the preset '{}' must be an object", name),
+                ),
+            }
+        }
+    }
+
+    let presets_dir = match config_path {
+        Some(path) => path.with_file_name("presets"),
+        None => match project_dirs() {
+            Some(dirs) => dirs.config_dir().join("presets"),
+            None => config_error(None, format!("unknown preset '{}'", name)),
+        },
+    };
+    ["toml", "json"]
+        .iter()
+        .map(|extension| presets_dir.join(format!("{}.{}", name, extension)))
+        .find(|path| path.exists())
+        .map(|path| {
+            let options = read_options(&path);
+            (path, options)
+        })
+        .unwrap_or_else(|| {
+            config_error(
+                config_path,
+                format!(
+                    "unknown preset '{}'; it isn't in the 'presets' table or the directory '{}'",
+                    name,
+                    presets_dir.display()
+                ),
+            )
+        })
+}
+
+/// Returns the platform's directories for evocaition's config and data, such as
+/// `$XDG_CONFIG_HOME/evocaition` on Linux, `~/Library/Application Support/evocaition` on
+/// macOS and `%APPDATA%\evocaition` on Windows.
+pub fn project_dirs() -> Option<ProjectDirs> {
+    ProjectDirs::from("", "", "evocaition")
+}
+
+/// Sets `value` from the environment variable `name` unless it was given on the command
+/// line, exiting with a usage error if the variable holds something that can't be parsed.
+fn env_default<T: FromStr>(value: &mut Option<T>, name: &str, on_command_line: bool)
+where
+    T::Err: fmt::Display,
+{
+    env_default_with(value, name, on_command_line, str::parse);
+}
+
+/// Works like `env_default`, but parses the variable with `parse`, such as one of the
+/// `value_parser`s that check the flag's range.
+fn env_default_with<T, E: fmt::Display>(
+    value: &mut Option<T>,
+    name: &str,
+    on_command_line: bool,
+    parse: impl Fn(&str) -> Result<T, E>,
+) {
+    if on_command_line {
+        return;
+    }
+    let Ok(raw) = env::var(name) else {
+        return;
+    };
+    match parse(raw.trim()) {
+        Ok(parsed) => *value = Some(parsed),
+        Err(e) => Config::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!(
+                    "invalid value '{}' for environment variable {}: {}",
+                    raw, name, e
+                ),
+            )
+            .exit(),
+    }
+}
+
+/// Serializes a secret so that only whether or not it was set is shown.
+fn redact<S: Serializer>(secret: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    if secret.is_empty() {
+        serializer.serialize_str("")
+    } else {
+        serializer.serialize_str("<redacted>")
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{io, path::Path, sync::Arc};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::mpsc,
+};
+
+use evocaition::{api::ApiClient, config::Config, error, style};
+
+// The request a client sends to the daemon as a single line of JSON
+#[derive(Debug, Serialize, Deserialize)]
+struct DaemonRequest {
+    prompt: String,
+}
+
+// The daemon's replies, each sent as a line of JSON: the response's text as it arrives,
+// then either `Done` or `Error` to end it
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DaemonReply {
+    Delta(String),
+    Done,
+    Error(String),
+}
+
+/// Serves prompts sent to the Unix socket at `path` until interrupted with Ctrl-C.
+///
+/// Every prompt is sent using the daemon's own configuration, with only the prompt coming
+/// from the client. The HTTP client and cached model parameters are kept between requests
+/// so that clients don't pay for starting up and connecting each time. A request is
+/// dropped as soon as its client can no longer be written to.
+pub async fn serve(config: Config, path: &str) -> Result<()> {
+    // a socket left behind by a daemon that didn't shut down cleanly would block binding
+    if Path::new(path).exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(anyhow!("A daemon is already listening on '{}'", path));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|e| anyhow!("Unable to listen on the socket '{}': {}", path, e))?;
+    style::report(&format!("listening on {}", path), config.use_color());
+
+    let warm = Arc::new(ApiClient::new(config.clone(), |_| Ok(())));
+    let result = loop {
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => break Err(e.into()),
+            },
+            _ = tokio::signal::ctrl_c() => break Ok(()),
+        };
+        let config = config.clone();
+        let warm = warm.clone();
+        tokio::spawn(async move {
+            let color = config.use_color();
+            match handle_connection(stream, config, &warm).await {
+                // the client went away before the response was finished
+                Err(e) if error::is_broken_pipe(&e) => {}
+                Err(e) => style::error(&e.to_string(), color),
+                Ok(()) => {}
+            }
+        });
+    };
+
+    let _ = std::fs::remove_file(path);
+    result
+}
+
+// Reads a request from the client and streams the response back to it.
+async fn handle_connection(stream: UnixStream, mut config: Config, warm: &ApiClient) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    if BufReader::new(reader).read_line(&mut line).await? == 0 {
+        // nothing was asked, like when another daemon checks whether this one is running
+        return Ok(());
+    }
+    let request: DaemonRequest = serde_json::from_str(&line)
+        .map_err(|e| anyhow!("Unable to parse the request from a client: {}", e))?;
+    config.prompt = Some(request.prompt);
+
+    let (reply, mut replies) = mpsc::unbounded_channel();
+    let deltas = reply.clone();
+    let completion = async move {
+        // the text is forwarded to the client as it arrives
+        let callback = move |s: &str| {
+            deltas
+                .send(DaemonReply::Delta(s.to_string()))
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+        };
+        let result = ApiClient::new(config, callback)
+            .with_shared_state(warm)
+            .do_completion()
+            .await;
+        let _ = reply.send(match result {
+            Ok(_) => DaemonReply::Done,
+            Err(e) => DaemonReply::Error(e.to_string()),
+        });
+    };
+
+    let forward = async {
+        while let Some(reply) = replies.recv().await {
+            let mut line = serde_json::to_string(&reply)?;
+            line.push('\n');
+            writer.write_all(line.as_bytes()).await?;
+        }
+        Ok::<(), anyhow::Error>(())
+    };
+    tokio::pin!(forward);
+
+    // forwarding only stops early if the client has gone away, and then the request is
+    // dropped rather than left generating text that nobody will read
+    tokio::select! {
+        () = completion => {}
+        forwarded = &mut forward => return forwarded,
+    }
+    forward.await
+}
+
+/// Sends the prompt to the daemon listening on the Unix socket at `path`, passing the text
+/// of the response to `callback` as it arrives.
+pub async fn request(
+    path: &str,
+    prompt: String,
+    mut callback: impl FnMut(&str) -> io::Result<()>,
+) -> Result<()> {
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow!("Unable to connect to the daemon at '{}': {}", path, e))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut line = serde_json::to_string(&DaemonRequest { prompt })?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str(&line)? {
+            DaemonReply::Delta(text) => callback(&text)?,
+            DaemonReply::Done => return Ok(()),
+            DaemonReply::Error(message) => return Err(anyhow!(message)),
+        }
+    }
+    Err(anyhow!(
+        "The daemon closed the connection before the response was finished"
+    ))
+}
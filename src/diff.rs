@@ -0,0 +1,44 @@
+/// Compares two texts line by line and describes how to get from `old` to `new`.
+///
+/// Lines only in `old` are prefixed with `- `, lines only in `new` with `+ ` and lines in
+/// both with two spaces, following the longest run of lines the two texts have in common.
+///
+/// # Parameters
+/// - `old` - The text to compare from.
+/// - `new` - The text to compare to.
+///
+/// # Returns
+/// - The diff with one line per line of the texts.
+pub fn line_diff(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.split('\n').collect();
+    let new: Vec<&str> = new.split('\n').collect();
+
+    // common[i][j] is the length of the longest common run of lines in old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(format!("  {}", old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(format!("- {}", old[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", new[j]));
+            j += 1;
+        }
+    }
+    lines.join("\n")
+}
@@ -0,0 +1,101 @@
+use anyhow::{anyhow, Result};
+use encoding_rs::{Decoder, DecoderResult, Encoding};
+use std::io::{self, Read};
+
+/// Looks up the encoding named by `label`, such as `utf-8`, `latin1` or `shift_jis`, using
+/// the labels from the WHATWG Encoding Standard.
+pub fn lookup(label: &str) -> Result<&'static Encoding> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| anyhow!("Unknown input encoding '{}'", label))
+}
+
+/// Decodes prompt input written in the encoding named by `label` into a `String`.
+///
+/// # Parameters
+/// - `bytes` - The input exactly as it was read.
+/// - `label` - The name of the encoding the input is written in.
+/// - `source` - Describes where the input came from, for the error message.
+///
+/// # Returns
+/// - The decoded text, or an `Err` if the input has bytes that aren't valid in the encoding.
+pub fn decode(bytes: &[u8], label: &str, source: &str) -> Result<String> {
+    let encoding = lookup(label)?;
+    decode_strict(bytes, encoding).map_err(|offset| {
+        anyhow!(
+            "{} isn't valid {} at byte {}; use '--input-encoding' to name the encoding it's written in",
+            source,
+            encoding.name(),
+            offset
+        )
+    })
+}
+
+/// Decodes `bytes` written in `encoding` into a `String`, refusing to replace bytes that
+/// aren't valid in it.
+///
+/// # Returns
+/// - The decoded text, or the offset into `bytes` of the first byte that isn't valid.
+pub fn decode_strict(bytes: &[u8], encoding: &'static Encoding) -> Result<String, usize> {
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut text = String::new();
+    decode_chunk(&mut decoder, bytes, 0, true, &mut text)
+        .map(|()| text)
+        .map_err(|offset| offset as usize)
+}
+
+/// Decodes the next chunk of a stream with `decoder`, appending the text to `text`. Unless
+/// it's the `last` chunk, a character cut off at the end of `bytes` is kept by the decoder
+/// until the chunk that finishes it.
+///
+/// # Parameters
+/// - `start` - How far into the stream `bytes` starts, for the offset of an invalid byte.
+///
+/// # Returns
+/// - `Ok` once all of `bytes` is decoded, or the offset into the stream of the first byte
+///   that isn't valid.
+pub fn decode_chunk(
+    decoder: &mut Decoder,
+    bytes: &[u8],
+    start: u64,
+    last: bool,
+    text: &mut String,
+) -> Result<(), u64> {
+    let mut offset = 0;
+    loop {
+        let remaining = &bytes[offset..];
+        let needed = decoder
+            .max_utf8_buffer_length_without_replacement(remaining.len())
+            .unwrap_or(remaining.len());
+        text.reserve(needed);
+        let (result, read) = decoder.decode_to_string_without_replacement(remaining, text, last);
+        offset += read;
+        match result {
+            DecoderResult::InputEmpty => return Ok(()),
+            DecoderResult::OutputFull => continue,
+            // the malformed sequence ends `extra` bytes before where the decoder stopped, and
+            // may have started in an earlier chunk
+            DecoderResult::Malformed(malformed, extra) => {
+                return Err(start + offset as u64 - extra as u64 - malformed as u64)
+            }
+        }
+    }
+}
+
+/// Reads the file at `path` as prompt input written in the encoding named by `label`.
+///
+/// # Parameters
+/// - `path` - The file to read.
+/// - `label` - The name of the encoding the file is written in.
+/// - `description` - What the file is used for, like `context file`, for error messages.
+pub fn read_file(path: &str, label: &str, description: &str) -> Result<String> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| anyhow!("Unable to read the {} '{}': {}", description, path, e))?;
+    decode(&bytes, label, &format!("The {} '{}'", description, path))
+}
+
+/// Reads all of stdin as prompt input written in the encoding named by `label`.
+pub fn read_stdin(label: &str) -> Result<String> {
+    let mut bytes = Vec::new();
+    io::stdin().read_to_end(&mut bytes)?;
+    decode(&bytes, label, "The prompt read from stdin")
+}
@@ -0,0 +1,122 @@
+use std::{fmt, io};
+
+use reqwest::StatusCode;
+
+/// The ways a request to the API can fail that are worth telling apart.
+///
+/// These are returned inside an `anyhow::Error`, so callers that care about the kind of
+/// failure can get it back with `downcast_ref::<ApiError>()`.
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    // The API rejected the key with a 401 or 403 status
+    Auth {
+        status: StatusCode,
+        message: String,
+    },
+
+    // Any other non-success status
+    Status {
+        status: StatusCode,
+        message: String,
+    },
+
+    // The API didn't connect or send anything for the `--timeout` number of seconds
+    Timeout {
+        seconds: u64,
+    },
+
+    // The prompt was refused by the provider's content moderation before any text was
+    // generated; `categories` lists why, when the provider says
+    Moderation {
+        categories: Vec<String>,
+        flagged_input: Option<String>,
+    },
+}
+
+impl ApiError {
+    /// Categorizes a non-success status returned by the API along with the error message
+    /// that came with it.
+    pub fn from_status(status: StatusCode, message: String) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ApiError::Auth { status, message },
+            _ => ApiError::Status { status, message },
+        }
+    }
+
+    /// Returns a short name for the kind of failure, for summaries of many failed requests.
+    pub fn category(&self) -> &'static str {
+        match self {
+            ApiError::Auth { .. } => "auth",
+            ApiError::Timeout { .. } => "timeout",
+            ApiError::Moderation { .. } => "moderation",
+            ApiError::Status { status, .. } if *status == StatusCode::TOO_MANY_REQUESTS => {
+                "rate limit"
+            }
+            ApiError::Status { status, .. } if status.is_server_error() => "server",
+            ApiError::Status { .. } => "request",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Auth { status, message } => write!(
+                f,
+                "authentication failed — check your API key (--key, --key-file or OPENROUTER_API_KEY)\nThe API responded with status {}: {}",
+                status, message
+            ),
+            ApiError::Status { status, message } => {
+                write!(f, "API request failed with status {}: {}", status, message)
+            }
+            ApiError::Timeout { seconds } => write!(
+                f,
+                "timed out after the API sent nothing for {}s (--timeout); the provider may be stalled or overloaded",
+                seconds
+            ),
+            ApiError::Moderation {
+                categories,
+                flagged_input,
+            } => {
+                write!(f, "your prompt was rejected by content moderation")?;
+                if !categories.is_empty() {
+                    write!(f, " (flagged for: {})", categories.join(", "))?;
+                }
+                write!(
+                    f,
+                    "\nThe input was refused before anything was generated; this isn't the model's output being filtered"
+                )?;
+                if let Some(flagged_input) = flagged_input {
+                    write!(f, "\nFlagged input: {}", flagged_input)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Returns a short name for the kind of failure behind `error`: one of the `ApiError`
+/// categories, `network` if the API couldn't be reached, or `other`.
+pub fn category(error: &anyhow::Error) -> &'static str {
+    if let Some(api_error) = error.downcast_ref::<ApiError>() {
+        return api_error.category();
+    }
+    if error
+        .chain()
+        .any(|cause| cause.downcast_ref::<reqwest::Error>().is_some())
+    {
+        return "network";
+    }
+    "other"
+}
+
+/// Returns true if the error came from writing to a pipe whose reader has gone away, like
+/// `head` does once it has read enough.
+pub fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == io::ErrorKind::BrokenPipe)
+}
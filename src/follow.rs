@@ -0,0 +1,239 @@
+use anyhow::{anyhow, Result};
+use encoding_rs::{Decoder, Encoding};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, Metadata},
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    time::Duration,
+};
+
+use evocaition::{config::FollowMode, encoding};
+
+// How long to wait before checking the followed file for new text again
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Watches a file like `tail -f` and splits the text appended to it into prompts.
+///
+/// Only text appended after the file was opened is read. If the file is truncated or
+/// replaced, such as by log rotation, it's read again from the beginning.
+pub struct Follower {
+    // The path of the file being watched
+    path: String,
+
+    // How the appended text is split into prompts
+    mode: FollowMode,
+
+    // The encoding the file is written in
+    encoding: &'static Encoding,
+
+    // How far into the file has been read
+    offset: u64,
+
+    // Identifies the file at `path` so that replacing it can be noticed
+    file_id: Option<u64>,
+
+    // Decodes the bytes as they're read, holding on to a character cut off at the end of a
+    // read until the rest of it arrives
+    decoder: Decoder,
+
+    // Text decoded after the last complete line
+    partial: String,
+
+    // The lines of the block being gathered in `FollowMode::Block`
+    block: Vec<String>,
+
+    // Prompts that have been read but not yet returned
+    prompts: VecDeque<String>,
+}
+
+impl Follower {
+    /// Starts following the file at `path` from its current end, decoding its text from the
+    /// encoding named by `encoding`.
+    ///
+    /// # Returns
+    /// - The `Follower`, or an `Err` if the file doesn't exist or can't be read.
+    pub fn open(path: &str, mode: FollowMode, encoding: &str) -> Result<Self> {
+        let encoding = encoding::lookup(encoding)?;
+        let metadata = fs::metadata(path)
+            .map_err(|e| anyhow!("Unable to follow the file '{}': {}", path, e))?;
+        Ok(Follower {
+            path: path.to_string(),
+            mode,
+            encoding,
+            offset: metadata.len(),
+            file_id: file_id(&metadata),
+            decoder: encoding.new_decoder_with_bom_removal(),
+            partial: String::new(),
+            block: Vec::new(),
+            prompts: VecDeque::new(),
+        })
+    }
+
+    /// Waits for the next prompt to be appended to the file.
+    pub async fn next_prompt(&mut self) -> Result<String> {
+        loop {
+            if let Some(prompt) = self.prompts.pop_front() {
+                return Ok(prompt);
+            }
+            self.read_appended()?;
+            if self.prompts.is_empty() {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+
+    /// Reads whatever has been appended to the file since the last call.
+    fn read_appended(&mut self) -> Result<()> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            // the file has been rotated away; wait for it to be recreated
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let file_id = file_id(&metadata);
+        if file_id != self.file_id || metadata.len() < self.offset {
+            self.file_id = file_id;
+            self.offset = 0;
+            self.decoder = self.encoding.new_decoder_with_bom_removal();
+            self.partial.clear();
+        }
+        if metadata.len() == self.offset {
+            return Ok(());
+        }
+
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        // lines are split after decoding, since in encodings like UTF-16 a newline is more
+        // than one byte and a `\n` byte can be part of another character
+        encoding::decode_chunk(
+            &mut self.decoder,
+            &bytes,
+            self.offset,
+            false,
+            &mut self.partial,
+        )
+        .map_err(|offset| {
+            anyhow!(
+                "The followed file '{}' isn't valid {} at byte {}; use '--input-encoding' to name the encoding it's written in",
+                self.path,
+                self.encoding.name(),
+                offset
+            )
+        })?;
+        self.offset += bytes.len() as u64;
+
+        while let Some(pos) = self.partial.find('\n') {
+            let line: String = self.partial.drain(..=pos).collect();
+            self.push_line(line.trim_end().to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Turns a complete line into a prompt, or adds it to the current block.
+    fn push_line(&mut self, line: String) {
+        match self.mode {
+            FollowMode::Line => {
+                if !line.trim().is_empty() {
+                    self.prompts.push_back(line);
+                }
+            }
+            FollowMode::Block => {
+                if !line.trim().is_empty() {
+                    self.block.push(line);
+                } else if !self.block.is_empty() {
+                    self.prompts.push_back(self.block.join("\n"));
+                    self.block.clear();
+                }
+            }
+        }
+    }
+}
+
+/// Returns the inode of the file on platforms that have them.
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+/// Returns the inode of the file on platforms that have them.
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::OpenOptions, io::Write, path::PathBuf};
+
+    // A file in the temp directory that's removed once the test is done with it
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("evocaition-{}-{}", std::process::id(), name));
+            fs::write(&path, b"").unwrap();
+            TempFile(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+
+        fn append(&self, bytes: &[u8]) {
+            let mut file = OpenOptions::new().append(true).open(&self.0).unwrap();
+            file.write_all(bytes).unwrap();
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn utf16le(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn a_utf16le_file_is_split_into_lines_after_decoding() {
+        let file = TempFile::new("follow-utf16le.txt");
+        let mut follower = Follower::open(file.path(), FollowMode::Line, "utf-16le").unwrap();
+
+        // U+010A and U+0A00 each have a 0x0A byte that isn't a newline
+        let appended = utf16le("first \u{010A}\nsecond \u{0A00}\r\nthi");
+        let (head, tail) = appended.split_at(appended.len() - 3);
+        file.append(head);
+        follower.read_appended().unwrap();
+        file.append(tail);
+        file.append(&utf16le("rd\n"));
+        follower.read_appended().unwrap();
+
+        assert_eq!(
+            follower.prompts,
+            ["first \u{010A}", "second \u{0A00}", "third"]
+        );
+    }
+
+    #[test]
+    fn an_invalid_byte_is_reported_with_its_offset_in_the_file() {
+        let file = TempFile::new("follow-invalid.txt");
+        let mut follower = Follower::open(file.path(), FollowMode::Line, "utf-8").unwrap();
+
+        file.append(b"hello\n");
+        follower.read_appended().unwrap();
+        file.append(b"ab\xFFcd\n");
+        let e = follower.read_appended().unwrap_err();
+
+        assert!(e.to_string().contains("at byte 8"), "{}", e);
+        assert_eq!(follower.prompts, ["hello"]);
+    }
+}
@@ -0,0 +1,38 @@
+//! Generates text with AI models through the OpenRouter API, or any other API that's
+//! compatible with it, and optionally Anthropic's and Ollama's.
+//!
+//! This is the library behind the `evocaition` command. An [`ApiClient`] sends the prompt
+//! in its [`Config`] and passes the text of the response to a callback as it arrives,
+//! returning the whole [`Completion`] once it's finished.
+//!
+//! The `Config` is usually parsed from the command line with `Config::from_cli`, but it can
+//! also be built in code by parsing an argument list that leaves everything at its default
+//! and then setting the fields that are needed:
+//!
+//! ```no_run
+//! use clap::Parser;
+//! use evocaition::{ApiClient, Config};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let mut config = Config::parse_from(["evocaition"]);
+//! config.api_key = std::env::var("OPENROUTER_API_KEY")?;
+//! config.prompt = Some("Write a haiku about the sea.".to_string());
+//!
+//! let completion = ApiClient::new(config, |_| Ok(())).do_completion().await?;
+//! println!("{}", completion.content);
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod api;
+pub mod config;
+pub mod encoding;
+pub mod error;
+mod mime;
+pub mod ratelimit;
+mod stats;
+pub mod style;
+pub mod transcript;
+
+pub use api::{ApiClient, ApiClientCallback, Completion, Usage};
+pub use config::Config;
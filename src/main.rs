@@ -1,26 +1,829 @@
-mod api;
-mod config;
+mod chat;
+#[cfg(all(unix, feature = "daemon"))]
+mod daemon;
+mod diff;
+mod follow;
+mod markdown;
+mod output;
+mod template;
 
-use std::{io::Write, process::exit};
+use std::{
+    io::{self, Write},
+    process::{exit, Stdio},
+    sync::{Arc, Mutex},
+};
 
-use api::ApiClient;
-use config::Config;
+use anyhow::{anyhow, Result};
+use evocaition::{
+    api::{ApiClient, ChatMessage, Completion, SessionUsage, Usage},
+    config::{Config, FollowMode, RampFormat, TempRamp},
+    encoding, error,
+    ratelimit::RateLimiter,
+    style, transcript,
+};
+use follow::Follower;
+use output::{OutputFile, OutputSink, SplitOutput};
+use serde_json::json;
+use tokio::{io::AsyncWriteExt, sync::Semaphore};
 
 #[tokio::main]
 async fn main() {
     // parse all of our command line arguments
     let config = Config::from_cli();
+    let color = config.use_color();
+
+    // run the actual API call...
+    if let Err(e) = run(config).await {
+        // the program reading the output has stopped, like `head` does once it has
+        // enough, so there's nothing left to do
+        if error::is_broken_pipe(&e) {
+            exit(0);
+        }
+        style::error(&e.to_string(), color);
+        exit(1);
+    }
+}
+
+// Runs whichever mode of operation the configuration asks for.
+async fn run(config: Config) -> Result<()> {
+    if config.echo_config {
+        println!("{}", serde_json::to_string_pretty(&config)?);
+        return Ok(());
+    }
+
+    if let (Some(count), Some(path)) = (config.list_recent, config.transcript_log.as_deref()) {
+        return list_recent(path, count);
+    }
+
+    if config.list_parameters {
+        return list_parameters(config).await;
+    }
+
+    if config.list_models {
+        return list_models(config).await;
+    }
+
+    if config.dry_run {
+        return ApiClient::new(config, |_| Ok(())).dry_run().await;
+    }
+
+    if let (Some(path), Some(template)) = (
+        config.batch_jsonl_input.clone(),
+        config.prompt_template.clone(),
+    ) {
+        return run_batch_jsonl(config, &path, &template).await;
+    }
+
+    if let Some(path) = config.batch_file.clone() {
+        return run_batch_file(config, &path).await;
+    }
+
+    if config.chat {
+        return chat::run(config, print_response).await;
+    }
+
+    #[cfg(all(unix, feature = "daemon"))]
+    if let Some(path) = config.daemon.clone() {
+        return daemon::serve(config, &path).await;
+    }
+
+    #[cfg(all(unix, feature = "daemon"))]
+    if let Some(path) = &config.client {
+        let prompt = match config.prompt.clone() {
+            Some(prompt) => prompt,
+            None => encoding::read_stdin(&config.input_encoding)?,
+        };
+        return daemon::request(path, prompt, print_response).await;
+    }
+
+    if let Some(path) = config.follow.clone() {
+        return run_follow(config, &path).await;
+    }
+
+    if let Some(template) = config.output_template.clone() {
+        return run_with_template(config, &template).await;
+    }
+
+    if config.deterministic_check {
+        return run_deterministic_check(config).await;
+    }
+
+    if config.json_stream_aggregate {
+        return run_json_stream_aggregate(config).await;
+    }
+
+    if config.as_sse {
+        return run_as_sse(config).await;
+    }
+
+    match config.temp_ramp {
+        Some(ramp) => run_temp_ramp(config, ramp).await,
+        None => run_completion(config).await,
+    }
+}
+
+// Sends the prompt and writes out the response as it arrives, then saves it to any
+// output files and runs the '--on-finish' command.
+async fn run_completion(config: Config) -> Result<()> {
+    // the text of the response is written to the output file as it arrives instead
+    let mut sinks = OutputSink::from_config(&config);
+    sinks.retain(|sink| !matches!(sink, OutputSink::Text { .. }));
+    let (file, split) = match (&config.output, config.split_output) {
+        (Some(path), Some(part_bytes)) => (
+            None,
+            Some(Arc::new(Mutex::new(SplitOutput::new(path, part_bytes)))),
+        ),
+        (Some(path), None) => (
+            Some(Arc::new(Mutex::new(OutputFile::create(
+                path,
+                config.append,
+            )?))),
+            None,
+        ),
+        (None, _) => (None, None),
+    };
+    let to_stdout = config.output.is_none() || config.tee;
+    let on_finish = config
+        .on_finish
+        .clone()
+        .map(|command| (command, config.clone()));
 
     // create the API text generator object and pass it a function that, when
-    // it gets a response from the AI, will just print out what it receives.
-    let api_client = ApiClient::new(config, |s: &str| {
-        print!("{}", s);
-        let _ = std::io::stdout().flush();
+    // it gets a response from the AI, will just print out what it receives,
+    // or write it to the output file or the parts of a split output.
+    let callback = {
+        let (file, split) = (file.clone(), split.clone());
+        move |s: &str| {
+            if to_stdout {
+                print_response(s)?;
+            }
+            if let Some(file) = &file {
+                file.lock().unwrap().write(s)?;
+            }
+            match &split {
+                Some(split) => split.lock().unwrap().write(s),
+                None => Ok(()),
+            }
+        }
+    };
+    let api_client = ApiClient::new(config, callback);
+    let result = match api_client.do_completion().await {
+        Ok(completion) => write_outputs(&sinks, &completion).map(|()| completion),
+        Err(e) => Err(e),
+    };
+    // whatever was received is kept in the output file even if the request failed
+    let result = match (result, file) {
+        (result, Some(file)) => {
+            let flushed = file.lock().unwrap().finish();
+            result.and_then(|completion| flushed.map(|()| completion))
+        }
+        (result, None) => result,
+    };
+    let result = match (result, split) {
+        (Ok(completion), Some(split)) => split.lock().unwrap().finish().map(|()| completion),
+        (result, _) => result,
+    };
+
+    if let Some((command, config)) = on_finish {
+        if result.is_ok() || config.on_finish_always {
+            run_on_finish(&command, &config, result.as_ref().ok()).await?;
+        }
+    }
+    result.map(|_| ())
+}
+
+// Runs the '--on-finish' command through the shell, passing it the text of the response
+// on stdin and what's known about the request in environment variables, then reports how
+// the command exited. `completion` is `None` if the request failed.
+async fn run_on_finish(
+    command: &str,
+    config: &Config,
+    completion: Option<&Completion>,
+) -> Result<()> {
+    let mut shell = match cfg!(windows) {
+        true => tokio::process::Command::new("cmd"),
+        false => tokio::process::Command::new("sh"),
+    };
+    shell
+        .arg(if cfg!(windows) { "/C" } else { "-c" })
+        .arg(command)
+        .stdin(Stdio::piped())
+        .env(
+            "EVOCAITION_MODEL",
+            completion
+                .and_then(|c| c.model.as_deref())
+                .unwrap_or(&config.model_id),
+        )
+        .env(
+            "EVOCAITION_TOKENS",
+            completion
+                .and_then(|c| c.usage.as_ref())
+                .map(|usage| usage.total_tokens.to_string())
+                .unwrap_or_default(),
+        )
+        .env(
+            "EVOCAITION_STATUS",
+            if completion.is_some() {
+                "success"
+            } else {
+                "error"
+            },
+        );
+    let mut child = shell
+        .spawn()
+        .map_err(|e| anyhow!("Unable to run the on-finish command '{}': {}", command, e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        let text = completion.map(|c| c.content.as_str()).unwrap_or_default();
+        // the command doesn't have to read its input, so a closed pipe isn't an error
+        let _ = stdin.write_all(text.as_bytes()).await;
+    }
+    let status = child.wait().await?;
+    if !status.success() {
+        style::warn(
+            &format!("the on-finish command exited with {}", status),
+            config.use_color(),
+        );
+    } else if !config.quiet {
+        style::report(
+            &format!("on-finish command exited with {}", status),
+            config.use_color(),
+        );
+    }
+    Ok(())
+}
+
+// Saves the finished completion to every output file that was asked for.
+fn write_outputs(sinks: &[OutputSink], completion: &Completion) -> Result<()> {
+    for sink in sinks {
+        sink.write(completion)?;
+    }
+    Ok(())
+}
+
+// Writes the text received from the AI to stdout as soon as it arrives.
+fn print_response(s: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    stdout.write_all(s.as_bytes())?;
+    stdout.flush()
+}
+
+// Runs the completion and writes it out through the output template once the
+// whole response has been received.
+async fn run_with_template(config: Config, output_template: &str) -> Result<()> {
+    let sinks = OutputSink::from_config(&config);
+    let completion = ApiClient::new(config, |_| Ok(())).do_completion().await?;
+    let rendered = template::render(output_template, |name| completion.template_value(name))
+        .map_err(|name| anyhow!("Unknown placeholder '{{{}}}' in the output template", name))?;
+    print_response(&rendered)?;
+
+    write_outputs(&sinks, &completion)
+}
+
+// Streams the completion from the API, for its faster first byte, but writes it out
+// as one JSON object with its metadata once the whole response has been received.
+async fn run_json_stream_aggregate(mut config: Config) -> Result<()> {
+    config.stream = true;
+    let sinks = OutputSink::from_config(&config);
+    let completion = ApiClient::new(config, |_| Ok(())).do_completion().await?;
+    println!("{}", serde_json::to_string_pretty(&completion)?);
+
+    write_outputs(&sinks, &completion)
+}
+
+// Writes the response to stdout as a server-sent event stream in the format of OpenAI's
+// streamed chat completions: a chunk for each piece of text as it arrives, or a single
+// one if the response isn't streamed, then a chunk with the finish reason and usage and
+// finally '[DONE]'. A failed request ends the stream with an error event.
+async fn run_as_sse(config: Config) -> Result<()> {
+    let sinks = OutputSink::from_config(&config);
+    let id = format!("gen-evocaition-{}", chrono::Utc::now().timestamp_millis());
+    let created = chrono::Utc::now().timestamp();
+    let model = config.model_id.clone();
+
+    let callback = {
+        let (id, model) = (id.clone(), model.clone());
+        move |s: &str| {
+            let chunk = sse_chunk(&id, created, &model, json!({ "content": s }), None, None);
+            print_response(&format!("data: {}\n\n", chunk))
+        }
+    };
+    let completion = match ApiClient::new(config, callback).do_completion().await {
+        Ok(completion) => completion,
+        Err(e) => {
+            let event = json!({ "error": { "message": e.to_string() } });
+            print_response(&format!("data: {}\n\n", event))?;
+            return Err(e);
+        }
+    };
+
+    let chunk = sse_chunk(
+        &id,
+        created,
+        completion.model.as_deref().unwrap_or(&model),
+        json!({}),
+        Some(completion.finish_reason.as_deref().unwrap_or("stop")),
+        completion.usage.as_ref(),
+    );
+    print_response(&format!("data: {}\n\ndata: [DONE]\n\n", chunk))?;
+
+    write_outputs(&sinks, &completion)
+}
+
+// Builds one chunk of an OpenAI style chat completion stream for '--as-sse'.
+fn sse_chunk(
+    id: &str,
+    created: i64,
+    model: &str,
+    delta: serde_json::Value,
+    finish_reason: Option<&str>,
+    usage: Option<&Usage>,
+) -> serde_json::Value {
+    let mut chunk = json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": created,
+        "model": model,
+        "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
     });
+    if let Some(usage) = usage {
+        chunk["usage"] = json!(usage);
+    }
+    chunk
+}
 
-    // run the actual API call...
-    if let Err(e) = api_client.do_completion().await {
-        eprintln!("ERROR: {}", e);
-        exit(1);
+// Sends the same request twice and reports whether the two outputs are byte-identical,
+// showing how they differ if they aren't. Differing outputs are an error when a seed
+// was given, since the provider then isn't honoring it.
+async fn run_deterministic_check(mut config: Config) -> Result<()> {
+    // both runs need the same prompt, so stdin can only be read once up front
+    if config.prompt.is_none() {
+        config.prompt = Some(encoding::read_stdin(&config.input_encoding)?);
+    }
+    let seeded = config.seed.is_some() || config.seed_from_prompt;
+    if !seeded {
+        style::warn(
+            "no seed was given with --seed or --seed-from-prompt, so the outputs may differ by design",
+            config.use_color(),
+        );
+    }
+
+    let session = Arc::new(SessionUsage::default());
+    let shared = ApiClient::new(config.clone(), |_| Ok(()));
+    let mut outputs = Vec::new();
+    for _ in 0..2 {
+        let completion = ApiClient::new(config.clone(), |_| Ok(()))
+            .with_shared_state(&shared)
+            .with_session(session.clone())
+            .do_completion()
+            .await?;
+        outputs.push(completion.content);
+    }
+
+    if outputs[0] == outputs[1] {
+        println!("outputs are identical ({} bytes)", outputs[0].len());
+        return Ok(());
+    }
+    println!(
+        "outputs differ:\n{}",
+        diff::line_diff(&outputs[0], &outputs[1])
+    );
+    match seeded {
+        true => Err(anyhow!("the outputs differ even though a seed was given")),
+        false => Ok(()),
+    }
+}
+
+// Runs the same prompt once for every temperature in the ramp, labeling each
+// output with the temperature that produced it.
+async fn run_temp_ramp(mut config: Config, ramp: TempRamp) -> Result<()> {
+    // every run needs the same prompt, so stdin can only be read once up front
+    if config.prompt.is_none() {
+        config.prompt = Some(encoding::read_stdin(&config.input_encoding)?);
+    }
+
+    // the runs differ only in temperature, so they share a connection to the API
+    let session = Arc::new(SessionUsage::default());
+    let shared = ApiClient::new(config.clone(), |_| Ok(()));
+    for step in 0..ramp.steps {
+        let temp = ramp.temp_at(step);
+        let mut run_config = config.clone();
+        run_config.temp = Some(temp);
+
+        match config.temp_ramp_format {
+            RampFormat::Blocks => {
+                println!("=== temperature {:.2} ===", temp);
+                ApiClient::new(run_config, print_response)
+                    .with_shared_state(&shared)
+                    .with_session(session.clone())
+                    .do_completion()
+                    .await?;
+                println!("\n");
+            }
+            RampFormat::Jsonl => {
+                let completion = ApiClient::new(run_config, |_| Ok(()))
+                    .with_shared_state(&shared)
+                    .with_session(session.clone())
+                    .do_completion()
+                    .await?;
+                println!(
+                    "{}",
+                    json!({"temperature": temp, "completion": completion.content})
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Sends each line or block appended to the followed file as a prompt, writing out
+// the responses as they arrive. A failed request is reported without stopping.
+async fn run_follow(config: Config, path: &str) -> Result<()> {
+    let mut follower = Follower::open(path, config.follow_mode, &config.input_encoding)?;
+    let mut history = Vec::new();
+    let session = Arc::new(SessionUsage::default());
+    let shared = ApiClient::new(config.clone(), |_| Ok(()));
+    // every exchange is exported, even when they aren't sent as history
+    let started = chrono::Local::now();
+    let mut exported = Vec::new();
+    loop {
+        let prompt = follower.next_prompt().await?;
+        let mut run_config = config.clone();
+        run_config.prompt = Some(prompt.clone());
+
+        let api_client = ApiClient::new(run_config, print_response)
+            .with_shared_state(&shared)
+            .with_history(history.clone())
+            .with_session(session.clone());
+        match api_client.do_completion().await {
+            Ok(completion) => {
+                let exchange = [
+                    ChatMessage {
+                        role: "user".to_string(),
+                        content: prompt,
+                    },
+                    ChatMessage {
+                        role: "assistant".to_string(),
+                        content: completion.content,
+                    },
+                ];
+                if let Some(export_path) = &config.export_markdown {
+                    exported.extend(exchange.iter().cloned());
+                    if let Err(e) =
+                        markdown::export(export_path, &config.model_id, &started, &exported)
+                    {
+                        style::warn(&e.to_string(), config.use_color());
+                    }
+                }
+                if config.follow_history {
+                    history.extend(exchange);
+                }
+            }
+            Err(e) => style::error(&e.to_string(), config.use_color()),
+        }
+
+        // keep the responses apart unless the user wants full control over whitespace
+        if !config.no_trailing_newline {
+            println!();
+        }
+    }
+}
+
+// Renders the prompt template with the fields of every JSON object in the input
+// file and writes each object back out as JSONL with its completion added. Lines
+// that can't be turned into a prompt or whose request fails are written out with an
+// 'error' field instead, so the output always has a line for every input line. With
+// '--echo-model-in-output', each completed object also gets a 'model' field naming the
+// model that generated its completion.
+async fn run_batch_jsonl(config: Config, path: &str, prompt_template: &str) -> Result<()> {
+    let input = encoding::read_file(path, &config.input_encoding, "batch input file")?;
+    let semaphore = Arc::new(Semaphore::new(config.concurrency as usize));
+    let session = Arc::new(SessionUsage::default());
+    let rate_limiter = Arc::new(RateLimiter::default());
+    // every request goes through the same HTTP client so that connections get reused
+    let shared = Arc::new(ApiClient::new(config.clone(), |_| Ok(())));
+
+    let mut tasks = Vec::new();
+    for (index, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut record = match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => {
+                let error = format!("line {} is not a JSON object", index + 1);
+                let failure = BatchFailure {
+                    line: index + 1,
+                    category: "input",
+                    message: error.clone(),
+                };
+                tasks.push(tokio::spawn(async move {
+                    (json!({ "error": error }), Some(failure))
+                }));
+                continue;
+            }
+        };
+        let prompt = template::render(prompt_template, |name| {
+            record.get(name).map(|value| match value {
+                serde_json::Value::String(text) => text.clone(),
+                value => value.to_string(),
+            })
+        });
+
+        let mut run_config = config.clone();
+        let semaphore = semaphore.clone();
+        let session = session.clone();
+        let rate_limiter = rate_limiter.clone();
+        let shared = shared.clone();
+        tasks.push(tokio::spawn(async move {
+            let failure = match prompt {
+                Ok(prompt) => {
+                    run_config.prompt = Some(prompt);
+                    let echo_model = run_config.echo_model_in_output;
+                    let model_id = run_config.model_id.clone();
+                    let _permit = semaphore.acquire().await;
+                    let api_client = ApiClient::new(run_config, |_| Ok(()))
+                        .with_shared_state(&shared)
+                        .with_session(session)
+                        .with_rate_limiter(rate_limiter);
+                    match api_client.do_completion().await {
+                        Ok(completion) => {
+                            record.insert("completion".to_string(), json!(completion.content));
+                            if echo_model {
+                                let model = completion.model.unwrap_or(model_id);
+                                record.insert("model".to_string(), json!(model));
+                            }
+                            None
+                        }
+                        Err(e) => {
+                            record.insert("error".to_string(), json!(e.to_string()));
+                            Some(BatchFailure {
+                                line: index + 1,
+                                category: error::category(&e),
+                                message: e.to_string(),
+                            })
+                        }
+                    }
+                }
+                Err(name) => {
+                    let error = format!("the field '{}' is missing", name);
+                    record.insert("error".to_string(), json!(error));
+                    Some(BatchFailure {
+                        line: index + 1,
+                        category: "input",
+                        message: error,
+                    })
+                }
+            };
+            (serde_json::Value::Object(record), failure)
+        }));
+    }
+
+    // write the results in the same order as the input
+    let total = tasks.len();
+    let mut failures = Vec::new();
+    for task in tasks {
+        let (result, failure) = task.await?;
+        println!("{}", result);
+        failures.extend(failure);
+    }
+
+    if config.summarize_errors {
+        return summarize_failures(&config, total, &failures);
+    }
+    Ok(())
+}
+
+// Writes the '--summarize-errors' report of a finished batch to stderr: how many prompts
+// succeeded and failed, then a line for each failure. Fails if any of the `total` prompts
+// did.
+fn summarize_failures(config: &Config, total: usize, failures: &[BatchFailure]) -> Result<()> {
+    let color = config.use_color();
+    style::report(
+        &format!(
+            "batch: {} succeeded, {} failed",
+            total - failures.len(),
+            failures.len()
+        ),
+        color,
+    );
+    for failure in failures {
+        // only the first line of the message, so each failure stays on one line
+        let message = failure.message.lines().next().unwrap_or_default();
+        style::report(
+            &format!(
+                "  line {} [{}] {}: {}",
+                failure.line, config.model_id, failure.category, message
+            ),
+            color,
+        );
+    }
+    if !failures.is_empty() {
+        return Err(anyhow!(
+            "{} of {} batch prompts failed",
+            failures.len(),
+            total
+        ));
+    }
+    Ok(())
+}
+
+// Sends every line, or every block of lines, of the batch file as its own prompt and
+// writes the responses out in the same order, with the batch delimiter between them. A
+// prompt whose request fails is reported on stderr and leaves its response empty, so the
+// responses still line up with the prompts. With '--echo-model-in-output', each response
+// starts with a '[model-id]' line naming the model that generated it.
+async fn run_batch_file(config: Config, path: &str) -> Result<()> {
+    let input = encoding::read_file(path, &config.input_encoding, "batch file")?;
+    let semaphore = Arc::new(Semaphore::new(config.concurrency as usize));
+    let session = Arc::new(SessionUsage::default());
+    let rate_limiter = Arc::new(RateLimiter::default());
+    // every request goes through the same HTTP client so that connections get reused
+    let shared = Arc::new(ApiClient::new(config.clone(), |_| Ok(())));
+
+    let mut tasks = Vec::new();
+    for (line, prompt) in split_prompts(&input, config.batch_mode) {
+        let mut run_config = config.clone();
+        run_config.prompt = Some(prompt);
+        let semaphore = semaphore.clone();
+        let session = session.clone();
+        let rate_limiter = rate_limiter.clone();
+        let shared = shared.clone();
+        tasks.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire().await;
+            let result = ApiClient::new(run_config, |_| Ok(()))
+                .with_shared_state(&shared)
+                .with_session(session)
+                .with_rate_limiter(rate_limiter)
+                .do_completion()
+                .await;
+            (line, result)
+        }));
+    }
+
+    // write the results in the same order as the input
+    let total = tasks.len();
+    let mut failures = Vec::new();
+    for (index, task) in tasks.into_iter().enumerate() {
+        if index > 0 {
+            println!("{}", config.batch_delimiter);
+        }
+        let (line, result) = task.await?;
+        match result {
+            Ok(completion) => {
+                if config.echo_model_in_output {
+                    let model = completion.model.as_deref().unwrap_or(&config.model_id);
+                    println!("[{}]", model);
+                }
+                print!("{}", completion.content);
+                if !completion.content.ends_with('\n') {
+                    println!();
+                }
+            }
+            Err(e) => {
+                let message = format!("prompt {} of {}: {}", index + 1, total, e);
+                style::error(&message, config.use_color());
+                failures.push(BatchFailure {
+                    line,
+                    category: error::category(&e),
+                    message: e.to_string(),
+                });
+            }
+        }
+    }
+
+    if config.summarize_errors {
+        return summarize_failures(&config, total, &failures);
+    }
+    if !failures.is_empty() {
+        return Err(anyhow!(
+            "{} of {} batch prompts failed",
+            failures.len(),
+            total
+        ));
+    }
+    Ok(())
+}
+
+// Splits the text of a batch file into prompts: each non-empty line, or each block of
+// lines ended by a blank line or the end of the file. Each prompt comes with the number
+// of the line it starts on.
+fn split_prompts(input: &str, mode: FollowMode) -> Vec<(usize, String)> {
+    let lines = input
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line));
+    match mode {
+        FollowMode::Line => lines
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| (number, line.to_string()))
+            .collect(),
+        FollowMode::Block => {
+            let mut prompts = Vec::new();
+            let mut block = Vec::new();
+            let mut start = 0;
+            for (number, line) in lines {
+                if !line.trim().is_empty() {
+                    if block.is_empty() {
+                        start = number;
+                    }
+                    block.push(line);
+                } else if !block.is_empty() {
+                    prompts.push((start, block.join("\n")));
+                    block.clear();
+                }
+            }
+            if !block.is_empty() {
+                prompts.push((start, block.join("\n")));
+            }
+            prompts
+        }
+    }
+}
+
+// A prompt of a batch that couldn't be completed, for the '--summarize-errors' report
+struct BatchFailure {
+    // The line of the input file the prompt came from
+    line: usize,
+
+    // The kind of failure, as categorized by `error::category`
+    category: &'static str,
+
+    // What went wrong
+    message: String,
+}
+
+// Prints the sampling parameters that the configured model supports.
+async fn list_parameters(config: Config) -> Result<()> {
+    let api_client = ApiClient::new(config, print_response);
+    let parameters = api_client.fetch_model_parameters().await?;
+    println!("Supported parameters for '{}':", parameters.model);
+    for parameter in parameters.supported_parameters {
+        println!("  {}", parameter);
+    }
+
+    Ok(())
+}
+
+// Prints the ID of each model available from the API, sorted, followed by its context
+// length and its price per million prompt and completion tokens where they're known.
+async fn list_models(config: Config) -> Result<()> {
+    let as_json = config.json;
+    let api_client = ApiClient::new(config, print_response);
+    if as_json {
+        let models = api_client.list_models_json().await?;
+        println!("{}", serde_json::to_string_pretty(&models)?);
+        return Ok(());
+    }
+
+    let mut models = api_client.list_models().await?;
+    models.sort_by(|a, b| a.id.cmp(&b.id));
+    let width = models.iter().map(|m| m.id.len()).max().unwrap_or_default();
+    for model in models {
+        let mut line = format!("{:width$}", model.id, width = width);
+        if let Some(context_length) = model.context_length {
+            line.push_str(&format!("  {:>8} tokens", context_length));
+        }
+        let per_million = |price: &Option<String>| {
+            price
+                .as_deref()
+                .and_then(|price| price.parse::<f64>().ok())
+                .filter(|price| *price >= 0.0)
+                .map(|price| format!("${:.2}", price * 1_000_000.0))
+        };
+        if let Some(pricing) = &model.pricing {
+            if let (Some(prompt), Some(completion)) = (
+                per_million(&pricing.prompt),
+                per_million(&pricing.completion),
+            ) {
+                line.push_str(&format!(
+                    "  {} in, {} out per 1M tokens",
+                    prompt, completion
+                ));
+            }
+        }
+        println!("{}", line.trim_end());
+    }
+
+    Ok(())
+}
+
+// Prints a one line summary of each of the most recent requests in the transcript log.
+fn list_recent(path: &str, count: usize) -> Result<()> {
+    for entry in transcript::read_recent(path, count)? {
+        let prompt = entry["prompt"].as_str().unwrap_or_default();
+        let mut summary: String = prompt.split_whitespace().collect::<Vec<_>>().join(" ");
+        if summary.chars().count() > 60 {
+            summary = summary.chars().take(57).collect::<String>() + "...";
+        }
+        println!(
+            "{}  {}  {}",
+            entry["timestamp"].as_str().unwrap_or_default(),
+            entry["model"].as_str().unwrap_or_default(),
+            summary
+        );
     }
+
+    Ok(())
 }
@@ -0,0 +1,41 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, SecondsFormat};
+use evocaition::api::ChatMessage;
+
+/// Writes a conversation to `path` as Markdown that's easy to read and share, replacing
+/// anything already there.
+///
+/// The model and when the conversation started go in the front matter, and each message
+/// follows under a bold heading naming who it's from. The messages are written as they
+/// are, so any Markdown in them, like fenced code blocks, comes through intact.
+///
+/// # Parameters
+/// - `path` - The file to write.
+/// - `model` - The model the conversation was had with.
+/// - `started` - When the conversation started.
+/// - `messages` - The conversation, oldest message first.
+pub fn export(
+    path: &str,
+    model: &str,
+    started: &DateTime<Local>,
+    messages: &[ChatMessage],
+) -> Result<()> {
+    let mut markdown = format!(
+        "---\nmodel: {}\ndate: {}\n---\n",
+        model,
+        started.to_rfc3339_opts(SecondsFormat::Secs, false)
+    );
+    for message in messages {
+        let mut speaker = message.role.clone();
+        if let Some(first) = speaker.get_mut(..1) {
+            first.make_ascii_uppercase();
+        }
+        markdown.push_str(&format!(
+            "\n**{}:**\n\n{}\n",
+            speaker,
+            message.content.trim_end()
+        ));
+    }
+    std::fs::write(path, markdown)
+        .map_err(|e| anyhow!("Unable to write the Markdown export '{}': {}", path, e))
+}
@@ -0,0 +1,66 @@
+use anyhow::{anyhow, Result};
+
+/// Determines the MIME type of a file to attach to a request.
+///
+/// The type is sniffed from the file's leading bytes, falling back to its extension when
+/// the bytes aren't recognized. PDF, WAV, MP3, WEBP, GIF, PNG and JPEG are supported.
+///
+/// # Parameters
+/// - `path` - The path the file was read from, used for its extension and in errors.
+/// - `data` - The contents of the file.
+///
+/// # Returns
+/// - The MIME type, or an `Err` if the type isn't one of the supported types.
+pub fn detect(path: &str, data: &[u8]) -> Result<&'static str> {
+    sniff(data).or_else(|| from_extension(path)).ok_or_else(|| {
+        anyhow!(
+            "Unable to determine the type of '{}'; supported types are pdf, wav, mp3, webp, gif, png and jpeg",
+            path
+        )
+    })
+}
+
+/// Returns the MIME type indicated by the magic bytes at the start of `data`, if recognized.
+fn sniff(data: &[u8]) -> Option<&'static str> {
+    let riff_type = match data {
+        [b'R', b'I', b'F', b'F', _, _, _, _, riff_type @ ..] => riff_type,
+        _ => &[],
+    };
+
+    if data.starts_with(b"%PDF-") {
+        Some("application/pdf")
+    } else if riff_type.starts_with(b"WAVE") {
+        Some("audio/wav")
+    } else if riff_type.starts_with(b"WEBP") {
+        Some("image/webp")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"ID3") {
+        Some("audio/mpeg")
+    } else {
+        // MP3 files without an ID3 tag start right away with a frame sync
+        match data {
+            [0xFF, second, ..] if second & 0xE0 == 0xE0 => Some("audio/mpeg"),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the MIME type for the extension of `path`, if it's a supported type.
+fn from_extension(path: &str) -> Option<&'static str> {
+    let extension = path.rsplit_once('.')?.1.to_ascii_lowercase();
+    match extension.as_str() {
+        "pdf" => Some("application/pdf"),
+        "wav" => Some("audio/wav"),
+        "mp3" => Some("audio/mpeg"),
+        "webp" => Some("image/webp"),
+        "gif" => Some("image/gif"),
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        _ => None,
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::{anyhow, Result};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+};
+
+use evocaition::{api::Completion, config::Config};
+
+/// A file that a finished completion is saved to in addition to stdout.
+pub enum OutputSink {
+    // The text of the response, added to the end of the file if `append` is set
+    Text { path: String, append: bool },
+
+    // The response and its metadata as a JSON object
+    Json(String),
+}
+
+impl OutputSink {
+    /// Returns the sinks requested by `--output` and `--output-json`.
+    pub fn from_config(config: &Config) -> Vec<OutputSink> {
+        let mut sinks = Vec::new();
+        // a split output is written as the response arrives instead
+        if let (Some(path), None) = (&config.output, config.split_output) {
+            sinks.push(OutputSink::Text {
+                path: path.clone(),
+                append: config.append,
+            });
+        }
+        if let Some(path) = &config.output_json {
+            sinks.push(OutputSink::Json(path.clone()));
+        }
+        sinks
+    }
+
+    /// Writes the completion to the sink's file, replacing anything already there unless
+    /// it's a text sink set to append.
+    pub fn write(&self, completion: &Completion) -> Result<()> {
+        match self {
+            OutputSink::Text { path, append } => {
+                let mut file = OutputFile::create(path, *append)?;
+                file.write(&completion.content)?;
+                file.finish()
+            }
+            OutputSink::Json(path) => {
+                std::fs::write(path, serde_json::to_string_pretty(completion)?)
+                    .map_err(|e| anyhow!("Unable to write the output file '{}': {}", path, e))
+            }
+        }
+    }
+}
+
+/// The `--output` file that the text of a response is written to as it arrives.
+pub struct OutputFile {
+    // The path of the file, for reporting errors
+    path: String,
+
+    file: BufWriter<File>,
+}
+
+impl OutputFile {
+    /// Opens the file at `path`, emptying it first unless `append` is set.
+    pub fn create(path: &str, append: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .map_err(|e| anyhow!("Unable to write the output file '{}': {}", path, e))?;
+        Ok(OutputFile {
+            path: path.to_string(),
+            file: BufWriter::new(file),
+        })
+    }
+
+    /// Adds `text` to the file.
+    pub fn write(&mut self, text: &str) -> io::Result<()> {
+        self.file.write_all(text.as_bytes())
+    }
+
+    /// Flushes whatever's left of the text out to the file.
+    pub fn finish(&mut self) -> Result<()> {
+        self.file
+            .flush()
+            .map_err(|e| anyhow!("Unable to write the output file '{}': {}", self.path, e))
+    }
+}
+
+/// Writes text as it arrives across numbered parts of a file, like `out.1`, `out.2` and so
+/// on, moving on to the next part once one reaches its size limit. Once finished, the parts
+/// are listed in a manifest next to them.
+pub struct SplitOutput {
+    // The path the parts are numbered after
+    path: String,
+
+    // The most bytes a part may hold
+    part_bytes: u64,
+
+    // The part being written and the bytes written to it so far
+    current: Option<(File, u64)>,
+
+    // The paths of the parts that have been started
+    parts: Vec<String>,
+}
+
+impl SplitOutput {
+    pub fn new(path: &str, part_bytes: u64) -> Self {
+        SplitOutput {
+            path: path.to_string(),
+            part_bytes,
+            current: None,
+            parts: Vec::new(),
+        }
+    }
+
+    /// Appends `text` to the current part, starting new ones as parts fill up. Characters
+    /// are never split across parts, so a part may be short of the limit.
+    pub fn write(&mut self, text: &str) -> io::Result<()> {
+        let mut rest = text;
+        while !rest.is_empty() {
+            let written = match &self.current {
+                Some((_, written)) if *written < self.part_bytes => *written,
+                _ => self.next_part()?,
+            };
+            let room = usize::try_from(self.part_bytes - written).unwrap_or(usize::MAX);
+            let mut end = rest.len().min(room);
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            if end == 0 {
+                if written > 0 {
+                    self.next_part()?;
+                    continue;
+                }
+                // a character bigger than the limit still has to go somewhere
+                end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+            }
+
+            let (file, written) = self.current.as_mut().expect("a part was just started");
+            file.write_all(&rest.as_bytes()[..end])?;
+            *written += end as u64;
+            rest = &rest[end..];
+        }
+        Ok(())
+    }
+
+    /// Writes the manifest listing the paths of the parts, one per line, to the path of the
+    /// output with `.manifest` added.
+    pub fn finish(&mut self) -> Result<()> {
+        let manifest = format!("{}.manifest", self.path);
+        let contents: String = self
+            .parts
+            .iter()
+            .map(|part| format!("{}\n", part))
+            .collect();
+        std::fs::write(&manifest, contents)
+            .map_err(|e| anyhow!("Unable to write the output manifest '{}': {}", manifest, e))
+    }
+
+    // Starts writing the next part, returning the bytes written to it so far.
+    fn next_part(&mut self) -> io::Result<u64> {
+        let path = format!("{}.{}", self.path, self.parts.len() + 1);
+        let file = File::create(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("unable to write the output file '{}': {}", path, e),
+            )
+        })?;
+        self.parts.push(path);
+        self.current = Some((file, 0));
+        Ok(0)
+    }
+}
@@ -0,0 +1,173 @@
+use chrono::DateTime;
+use reqwest::header::HeaderMap;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// The budget counts as low once no more than this fraction of the limit remains
+const LOW_REMAINING_FRACTION: f64 = 0.1;
+
+// The budget counts as low at or below this many requests when the limit isn't reported
+const LOW_REMAINING_REQUESTS: u64 = 5;
+
+// The header names used for the request budget by OpenRouter, OpenAI and Anthropic in turn
+const REMAINING_HEADERS: [&str; 3] = [
+    "x-ratelimit-remaining",
+    "x-ratelimit-remaining-requests",
+    "anthropic-ratelimit-requests-remaining",
+];
+const LIMIT_HEADERS: [&str; 3] = [
+    "x-ratelimit-limit",
+    "x-ratelimit-limit-requests",
+    "anthropic-ratelimit-requests-limit",
+];
+const RESET_HEADERS: [&str; 3] = [
+    "x-ratelimit-reset",
+    "x-ratelimit-reset-requests",
+    "anthropic-ratelimit-requests-reset",
+];
+
+/// The request budget last reported by the API through its rate limit headers.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    // The requests allowed in the current window, if reported
+    pub limit: Option<u64>,
+
+    // The requests left in the current window
+    pub remaining: u64,
+
+    // When the window ends and the budget is restored, if reported
+    pub reset: Option<Instant>,
+}
+
+impl RateLimit {
+    /// Returns true if so little of the budget remains that requests should be spread out.
+    fn is_low(&self) -> bool {
+        match self.limit {
+            Some(limit) => self.remaining as f64 <= limit as f64 * LOW_REMAINING_FRACTION,
+            None => self.remaining <= LOW_REMAINING_REQUESTS,
+        }
+    }
+}
+
+/// Tracks the rate limit reported by the API, shared by the clients of a session so that
+/// once the budget runs low they slow down enough for it to last until it resets, rather
+/// than running into `429` responses.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    // The most recently reported budget, less the requests reserved since
+    latest: Mutex<Option<RateLimit>>,
+
+    // The earliest time the next throttled request may be sent
+    next_slot: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    /// Records the budget reported by the rate limit headers of a response.
+    ///
+    /// # Returns
+    /// - The budget, or `None` if the response didn't report one.
+    pub fn record(&self, headers: &HeaderMap) -> Option<RateLimit> {
+        let remaining = header_value(headers, &REMAINING_HEADERS)?.parse().ok()?;
+        let limit = header_value(headers, &LIMIT_HEADERS).and_then(|v| v.parse().ok());
+        let reset = header_value(headers, &RESET_HEADERS).and_then(parse_reset);
+        let rate_limit = RateLimit {
+            limit,
+            remaining,
+            reset,
+        };
+        *self.latest.lock().unwrap() = Some(rate_limit);
+        Some(rate_limit)
+    }
+
+    /// Reserves a request against the budget, working out how long to wait before sending it.
+    ///
+    /// While the budget is low, requests are given evenly spaced slots across the time left
+    /// until it resets; once it's used up, they wait for the reset.
+    ///
+    /// # Returns
+    /// - How long to wait, or `None` if the request can be sent right away.
+    pub fn reserve(&self) -> Option<Duration> {
+        let mut latest = self.latest.lock().unwrap();
+        let rate_limit = latest.as_mut()?;
+        let now = Instant::now();
+        let reset = rate_limit.reset.filter(|reset| *reset > now)?;
+        if !rate_limit.is_low() {
+            rate_limit.remaining = rate_limit.remaining.saturating_sub(1);
+            return None;
+        }
+
+        let mut next_slot = self.next_slot.lock().unwrap();
+        let mut slot = next_slot.unwrap_or(now).max(now);
+        if rate_limit.remaining == 0 {
+            slot = slot.max(reset);
+        }
+        let interval = (reset - now)
+            / u32::try_from(rate_limit.remaining)
+                .unwrap_or(u32::MAX)
+                .saturating_add(1);
+        *next_slot = Some(slot + interval);
+        rate_limit.remaining = rate_limit.remaining.saturating_sub(1);
+
+        Some(slot - now).filter(|delay| !delay.is_zero())
+    }
+}
+
+/// Returns the value of the first of the headers that's present.
+fn header_value<'a>(headers: &'a HeaderMap, names: &[&str]) -> Option<&'a str> {
+    names
+        .iter()
+        .find_map(|name| headers.get(*name))
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Works out when the budget resets from a reset header, which may be a Unix timestamp in
+/// seconds or milliseconds (OpenRouter), a duration like `6m0s` (OpenAI) or an RFC 3339
+/// date (Anthropic).
+fn parse_reset(value: &str) -> Option<Instant> {
+    let value = value.trim();
+    let until = if let Ok(number) = value.parse::<f64>() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        if number > 1e12 {
+            Duration::from_millis(number as u64).checked_sub(now)?
+        } else if number > 1e9 {
+            Duration::try_from_secs_f64(number).ok()?.checked_sub(now)?
+        } else {
+            Duration::try_from_secs_f64(number).ok()?
+        }
+    } else if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        SystemTime::from(date)
+            .duration_since(SystemTime::now())
+            .ok()?
+    } else {
+        parse_duration(value)?
+    };
+    Instant::now().checked_add(until)
+}
+
+/// Parses a duration written as numbers with units, such as `1m30s`, `1.5s` or `250ms`.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let number_end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .filter(|end| *end > 0)?;
+        let number: f64 = rest[..number_end].parse().ok()?;
+        rest = &rest[number_end..];
+        let unit_end = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let seconds = match &rest[..unit_end] {
+            "ms" => 0.001,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return None,
+        };
+        total += number * seconds;
+        rest = &rest[unit_end..];
+    }
+    Duration::try_from_secs_f64(total).ok()
+}
@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use crate::api::Usage;
+
+// The upper bounds of the inter-token latency histogram buckets, in milliseconds
+const BUCKET_BOUNDS_MS: [u128; 7] = [5, 10, 25, 50, 100, 250, 500];
+
+// The width of the longest bar in the histogram
+const MAX_BAR_WIDTH: usize = 40;
+
+// Prompts larger than this are flagged, since they often carry far more context than intended
+const LARGE_PROMPT_TOKENS: u64 = 16_000;
+
+/// Describes the spread of the gaps between streamed deltas with the min, median, p95 and
+/// max latency followed by a small histogram.
+///
+/// # Returns
+/// - The multi-line report, or `None` if there are no gaps to describe.
+pub fn latency_report(gaps: &[Duration]) -> Option<String> {
+    if gaps.is_empty() {
+        return None;
+    }
+    let mut sorted = gaps.to_vec();
+    sorted.sort();
+
+    let mut report = format!(
+        "inter-token latency: min {}ms, median {}ms, p95 {}ms, max {}ms",
+        sorted[0].as_millis(),
+        percentile(&sorted, 0.5).as_millis(),
+        percentile(&sorted, 0.95).as_millis(),
+        sorted[sorted.len() - 1].as_millis()
+    );
+
+    let mut counts = [0usize; BUCKET_BOUNDS_MS.len() + 1];
+    for gap in &sorted {
+        let bucket = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| gap.as_millis() < *bound)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        counts[bucket] += 1;
+    }
+    let most = counts.iter().copied().max().unwrap_or(1);
+    for (bucket, count) in counts.iter().enumerate() {
+        let label = match bucket {
+            0 => format!("<{}ms", BUCKET_BOUNDS_MS[0]),
+            b if b == BUCKET_BOUNDS_MS.len() => format!(">={}ms", BUCKET_BOUNDS_MS[b - 1]),
+            b => format!("{}-{}ms", BUCKET_BOUNDS_MS[b - 1], BUCKET_BOUNDS_MS[b]),
+        };
+        let bar = "#".repeat((count * MAX_BAR_WIDTH).div_ceil(most));
+        report.push_str(&format!(
+            "\n  {:>9} {:<width$} {}",
+            label,
+            bar,
+            count,
+            width = MAX_BAR_WIDTH
+        ));
+    }
+
+    Some(report)
+}
+
+/// Describes how many completion tokens were generated for each prompt token, with a
+/// warning if the prompt was unusually large.
+///
+/// # Parameters
+/// - `label`: What the usage covers, such as `request` or `session`.
+/// - `usage`: The token usage to describe.
+///
+/// # Returns
+/// - The report, one line per finding.
+pub fn usage_ratio_report(label: &str, usage: &Usage) -> String {
+    let mut report = match usage.prompt_tokens {
+        0 => format!("{} ratio: n/a (no prompt tokens reported)", label),
+        prompt => format!(
+            "{} ratio: {:.2} completion tokens per prompt token",
+            label,
+            usage.completion_tokens as f64 / prompt as f64
+        ),
+    };
+    if usage.prompt_tokens > LARGE_PROMPT_TOKENS {
+        report.push_str(&format!(
+            "\nWARNING: the {} used {} prompt tokens; check for accidentally included context",
+            label, usage.prompt_tokens
+        ));
+    }
+    report
+}
+
+/// Returns the value at the `p` quantile of the already sorted `values`.
+fn percentile(values: &[Duration], p: f64) -> Duration {
+    let rank = (p * values.len() as f64).ceil() as usize;
+    values[rank.saturating_sub(1).min(values.len() - 1)]
+}
@@ -0,0 +1,55 @@
+// The ANSI escape that puts the terminal's text back to normal
+const RESET: &str = "\x1b[0m";
+
+/// The kinds of message written to stderr, each colored differently so that they stand
+/// apart from the response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    // Informational lines like the token usage, finish reason and retry notices
+    Diagnostic,
+
+    // Something that went wrong but didn't stop the request
+    Warning,
+
+    // The error that ended the program
+    Error,
+}
+
+impl Style {
+    /// Returns the ANSI escape that starts text in this style.
+    fn escape(&self) -> &'static str {
+        match self {
+            Style::Diagnostic => "\x1b[36m",
+            Style::Warning => "\x1b[33m",
+            Style::Error => "\x1b[31m",
+        }
+    }
+}
+
+/// Writes `message` to stderr as a diagnostic, colored if `color` is set.
+pub fn report(message: &str, color: bool) {
+    eprintln!("{}", paint(message, Style::Diagnostic, color));
+}
+
+/// Writes `message` to stderr as a warning, prefixed with `WARNING: ` and colored if `color`
+/// is set.
+pub fn warn(message: &str, color: bool) {
+    let message = format!("WARNING: {}", message);
+    eprintln!("{}", paint(&message, Style::Warning, color));
+}
+
+/// Writes `message` to stderr as an error, prefixed with `ERROR: ` and colored if `color` is
+/// set.
+pub fn error(message: &str, color: bool) {
+    let message = format!("ERROR: {}", message);
+    eprintln!("{}", paint(&message, Style::Error, color));
+}
+
+/// Wraps `text` in the ANSI escapes for `style` if `color` is set, or returns it unchanged
+/// otherwise.
+pub fn paint(text: &str, style: Style, color: bool) -> String {
+    match color {
+        true => format!("{}{}{}", style.escape(), text, RESET),
+        false => text.to_string(),
+    }
+}
@@ -0,0 +1,42 @@
+/// Renders a template by replacing each `{name}` placeholder with the value returned by `lookup`.
+///
+/// Literal braces can be written as `{{` and `}}`. An unterminated placeholder is copied to
+/// the output unchanged.
+///
+/// # Parameters
+/// - `template` - The text containing the placeholders.
+/// - `lookup` - Returns the value for a placeholder name, or `None` if the name is unknown.
+///
+/// # Returns
+/// - The rendered text, or the name of the first placeholder that `lookup` didn't have a value for.
+pub fn render<F>(template: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(pos) = rest.find(['{', '}']) {
+        rendered.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            rendered.push_str(&tail[..1]);
+            rest = &tail[2..];
+        } else if let Some(after) = tail.strip_prefix('}') {
+            rendered.push('}');
+            rest = after;
+        } else if let Some(end) = tail.find('}') {
+            let name = tail[1..end].trim();
+            let value = lookup(name).ok_or_else(|| name.to_string())?;
+            rendered.push_str(&value);
+            rest = &tail[end + 1..];
+        } else {
+            rendered.push_str(tail);
+            rest = "";
+        }
+    }
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
@@ -0,0 +1,55 @@
+use anyhow::{anyhow, Result};
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+// How much of the log is read at a time when searching backwards for recent entries
+const READ_BLOCK_SIZE: u64 = 8192;
+
+/// Appends an entry to the transcript log as a single line of JSON, creating the log
+/// if it doesn't exist yet.
+pub fn append(path: &str, entry: &serde_json::Value) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", entry)?;
+    Ok(())
+}
+
+/// Reads the last `count` entries of the transcript log, oldest first.
+///
+/// The log is read backwards from its end a block at a time so that only the recent part
+/// of a large log is read. Lines that aren't valid JSON are skipped.
+///
+/// # Returns
+/// - The entries, or an `Err` if the log couldn't be read.
+pub fn read_recent(path: &str, count: usize) -> Result<Vec<serde_json::Value>> {
+    let mut file = File::open(path)
+        .map_err(|e| anyhow!("Unable to read the transcript log '{}': {}", path, e))?;
+    let mut position = file.seek(SeekFrom::End(0))?;
+
+    // gather blocks from the end until they hold `count` complete lines after the first newline
+    let mut tail = Vec::new();
+    while position > 0 && tail.iter().filter(|b| **b == b'\n').count() <= count {
+        let read_size = position.min(READ_BLOCK_SIZE);
+        position -= read_size;
+        file.seek(SeekFrom::Start(position))?;
+        let mut block = vec![0; read_size as usize];
+        file.read_exact(&mut block)?;
+        block.extend_from_slice(&tail);
+        tail = block;
+    }
+
+    let text = String::from_utf8_lossy(&tail);
+    let mut lines: Vec<&str> = text.lines().collect();
+    // the first line is likely cut off unless the start of the log was reached
+    if position > 0 && !lines.is_empty() {
+        lines.remove(0);
+    }
+
+    let entries: Vec<serde_json::Value> = lines
+        .iter()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    let skip = entries.len().saturating_sub(count);
+    Ok(entries.into_iter().skip(skip).collect())
+}